@@ -0,0 +1,4 @@
+fn main() {
+    // The template is embedded with include_dir!, rebuild when it changes
+    println!("cargo:rerun-if-changed=template");
+}
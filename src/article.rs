@@ -0,0 +1,604 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::sync::LazyLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    Kz,
+    Ru,
+    En,
+}
+
+/// Metadata of one article, parsed from its split LaTeX chunk
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Article {
+    pub irsti: Option<String>,
+    pub emails: Vec<String>,
+    pub references: Vec<String>,
+    /// Title block per language, in the order they appear in the article
+    pub blocks: Vec<ArticleBlock>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArticleBlock {
+    pub lang: Lang,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub affiliations: Vec<String>,
+    #[serde(rename = "abstract")]
+    pub abstract_text: String,
+    pub keywords: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Id,
+    Abstract(Lang),
+    Keywords(Lang),
+    ReferencesHeading,
+    AuthorsInfo,
+    Numbered,
+    Text,
+}
+
+/// A blank-line separated paragraph of the article
+struct Paragraph<'a> {
+    /// Byte offset of `raw` in the article text
+    start: usize,
+    raw: &'a str,
+    plain: String,
+    kind: Kind,
+}
+
+static RE_ID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\\id\{(?:IRSTI|ҒТАМР|МРНТИ|ГРНТИ)\s*([0-9 .,]*)\}\{[^}]*\}").unwrap()
+});
+static RE_ABSTRACT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^(abstract|annotation|аннотация|резюме|аңдатпа|андатпа|түйіндеме)\b\s*[.:–-]?\s*",
+    )
+    .unwrap()
+});
+static RE_KEYWORDS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(key\s*words|ключевые слова|түйін(?:ді)? сөздер)\b\s*[.:–-]?\s*").unwrap()
+});
+static RE_REFERENCES: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^(references|литература|список (?:использованной |использованных )?(?:литературы|источников)|(?:пайдаланылған )?әдебиеттер(?: тізімі)?)[.:]?$",
+    )
+    .unwrap()
+});
+static RE_AUTHORS_INFO: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(information about (?:the )?authors|сведения об авторах|авторлар туралы (?:мәлімет|ақпарат))")
+        .unwrap()
+});
+static RE_NUMBERED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d+\.?\s").unwrap());
+/// Initials such as "A.B." or "А. Б." next to a surname
+static RE_INITIALS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\b\p{Lu}\.\s?(?:\p{Lu}\.)?\s?\p{Lu}\p{Ll}+|\p{Lu}\p{Ll}+\s\p{Lu}\.\s?(?:\p{Lu}\.)?",
+    )
+    .unwrap()
+});
+static RE_EMAIL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\w.+-]+@[\w-]+(?:\.[\w-]+)+").unwrap());
+
+impl Article {
+    /// Parses one chunk produced by `LatexStringExt::split_articles`
+    pub fn parse(text: &str) -> Self {
+        let mut article = Article {
+            irsti: RE_ID
+                .captures(text)
+                .map(|caps| caps[1].trim().to_string())
+                .filter(|code| !code.is_empty()),
+            ..Default::default()
+        };
+
+        let paragraphs = split_paragraphs(text);
+
+        for email in RE_EMAIL.find_iter(&text.replace(r"\_", "_")) {
+            let email = email.as_str().trim_end_matches('.').to_string();
+            if !article.emails.contains(&email) {
+                article.emails.push(email);
+            }
+        }
+
+        let abstracts: Vec<(usize, Lang)> = paragraphs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| match p.kind {
+                Kind::Abstract(lang) => Some((i, lang)),
+                _ => None,
+            })
+            .collect();
+
+        let mut lower = 0;
+        let mut header_starts = Vec::new();
+        for (n, &(index, lang)) in abstracts.iter().enumerate() {
+            let start = header_start(&paragraphs, lower, index);
+            header_starts.push(start);
+
+            let upper = abstracts.get(n + 1).map_or(paragraphs.len(), |&(i, _)| i);
+            let keywords = paragraphs[index + 1..upper]
+                .iter()
+                .find(|p| p.kind == Kind::Keywords(lang))
+                .map(|p| split_keywords(&RE_KEYWORDS.replace(&p.plain, "")))
+                .unwrap_or_default();
+
+            let mut block = parse_header(&paragraphs[start..index], lang);
+            block.abstract_text = RE_ABSTRACT
+                .replace(&paragraphs[index].plain, "")
+                .into_owned();
+            block.keywords = keywords;
+            article.blocks.push(block);
+
+            lower = index + 1;
+        }
+
+        // References run from a heading to the next title block or author info
+        let mut i = 0;
+        while i < paragraphs.len() {
+            if paragraphs[i].kind != Kind::ReferencesHeading {
+                i += 1;
+                continue;
+            }
+            i += 1;
+            while i < paragraphs.len()
+                && !header_starts.contains(&i)
+                && !matches!(
+                    paragraphs[i].kind,
+                    Kind::ReferencesHeading | Kind::AuthorsInfo | Kind::Abstract(_)
+                )
+            {
+                for line in paragraphs[i].raw.lines() {
+                    let line = strip_latex(line);
+                    let line = RE_NUMBERED.replace(&line, "");
+                    if !line.is_empty() {
+                        article.references.push(line.into_owned());
+                    }
+                }
+                i += 1;
+            }
+        }
+
+        article
+    }
+}
+
+fn split_paragraphs(text: &str) -> Vec<Paragraph<'_>> {
+    let mut paragraphs = Vec::new();
+    let mut offset = 0;
+
+    for piece in text.split("\n\n") {
+        let start = offset + piece.len() - piece.trim_start().len();
+        offset += piece.len() + 2;
+
+        let raw = piece.trim();
+        if raw.is_empty() {
+            continue;
+        }
+        let plain = strip_latex(raw);
+        let kind = classify(raw, &plain);
+        paragraphs.push(Paragraph {
+            start,
+            raw,
+            plain,
+            kind,
+        });
+    }
+
+    paragraphs
+}
+
+fn classify(raw: &str, plain: &str) -> Kind {
+    if RE_ID.is_match(raw) && raw.starts_with(r"\id{") {
+        return Kind::Id;
+    }
+    if let Some(caps) = RE_ABSTRACT.captures(plain) {
+        return Kind::Abstract(marker_lang(&caps[1]));
+    }
+    if let Some(caps) = RE_KEYWORDS.captures(plain) {
+        return Kind::Keywords(marker_lang(&caps[1]));
+    }
+    if RE_REFERENCES.is_match(plain) {
+        return Kind::ReferencesHeading;
+    }
+    if RE_AUTHORS_INFO.is_match(plain) {
+        return Kind::AuthorsInfo;
+    }
+    if RE_NUMBERED.is_match(plain) {
+        return Kind::Numbered;
+    }
+    Kind::Text
+}
+
+fn marker_lang(marker: &str) -> Lang {
+    let marker = marker.to_lowercase();
+    if marker.is_ascii() {
+        Lang::En
+    } else if ["аннотация", "резюме", "ключевые слова"].contains(&marker.as_str())
+    {
+        Lang::Ru
+    } else {
+        Lang::Kz
+    }
+}
+
+/// Walks back from an abstract over the title, author and affiliation lines
+fn header_start(paragraphs: &[Paragraph], lower: usize, abstract_index: usize) -> usize {
+    const MAX_HEADER_PARAGRAPHS: usize = 8;
+    const MAX_HEADER_LENGTH: usize = 300;
+
+    let mut start = abstract_index;
+    while start > lower
+        && abstract_index - start < MAX_HEADER_PARAGRAPHS
+        && paragraphs[start - 1].kind == Kind::Text
+        && paragraphs[start - 1].plain.chars().count() <= MAX_HEADER_LENGTH
+    {
+        start -= 1;
+    }
+    start
+}
+
+/// Positions of the title, author and affiliation lines within a header
+struct HeaderRoles {
+    title: Vec<usize>,
+    authors: Option<usize>,
+    /// Affiliation and e-mail lines, in order
+    affiliations: Vec<usize>,
+}
+
+impl HeaderRoles {
+    fn new(header: &[Paragraph]) -> Self {
+        let lines: Vec<usize> = (0..header.len())
+            .filter(|&i| !is_email_line(&header[i].plain))
+            .collect();
+
+        let authors = lines
+            .iter()
+            .take(3)
+            .position(|&i| RE_INITIALS.is_match(&header[i].plain));
+
+        let (title, authors, rest) = match authors {
+            Some(0) => (
+                lines.get(1).copied().into_iter().collect(),
+                Some(lines[0]),
+                lines.get(2).copied(),
+            ),
+            Some(k) => (
+                lines[..k].to_vec(),
+                Some(lines[k]),
+                lines.get(k + 1).copied(),
+            ),
+            None => (
+                lines.first().copied().into_iter().collect(),
+                None,
+                lines.get(1).copied(),
+            ),
+        };
+
+        // Everything after the author line (or the title) is affiliation,
+        // e-mail lines included
+        let first_affiliation = rest.unwrap_or(header.len());
+        HeaderRoles {
+            title,
+            authors,
+            affiliations: (first_affiliation..header.len()).collect(),
+        }
+    }
+}
+
+/// Splits header paragraphs into title, authors and affiliations
+fn parse_header(header: &[Paragraph], lang: Lang) -> ArticleBlock {
+    let roles = HeaderRoles::new(header);
+
+    ArticleBlock {
+        lang,
+        title: roles
+            .title
+            .iter()
+            .map(|&i| header[i].plain.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        authors: roles
+            .authors
+            .map(|i| split_authors(header[i].raw))
+            .unwrap_or_default(),
+        affiliations: roles
+            .affiliations
+            .iter()
+            .filter(|&&i| !is_email_line(&header[i].plain))
+            .map(|&i| {
+                header[i]
+                    .plain
+                    .trim_start_matches(is_marker)
+                    .trim()
+                    .to_string()
+            })
+            .collect(),
+        abstract_text: String::new(),
+        keywords: Vec::new(),
+    }
+}
+
+/// Wraps the title block at the top of an article in the journal macros:
+/// `\swa{authors}{title}` for the heading and TOC entry, the author line in
+/// `header` with ORCID links as `\alink`, and the affiliations in `affil`.
+pub fn markup_title_block(text: &str) -> String {
+    if text.contains(r"\swa{") {
+        return text.to_string();
+    }
+
+    let paragraphs = split_paragraphs(text);
+    let Some(abstract_index) = paragraphs
+        .iter()
+        .position(|p| matches!(p.kind, Kind::Abstract(_)))
+    else {
+        return text.to_string();
+    };
+
+    let start = header_start(&paragraphs, 0, abstract_index);
+    let header = &paragraphs[start..abstract_index];
+    let roles = HeaderRoles::new(header);
+    if roles.title.is_empty() {
+        return text.to_string();
+    }
+
+    let title = roles
+        .title
+        .iter()
+        .map(|&i| strip_outer_bold(header[i].raw))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let authors = roles
+        .authors
+        .map(|i| split_authors(header[i].raw).join(", "))
+        .unwrap_or_default();
+
+    let mut markup = format!("\\swa{{{authors}}}{{{title}}}");
+    if let Some(i) = roles.authors {
+        markup.push_str(&format!(
+            "\n\n\\begin{{header}}\n{}\n\\end{{header}}",
+            orcid_to_alink(header[i].raw)
+        ));
+    }
+    if !roles.affiliations.is_empty() {
+        let affiliations = roles
+            .affiliations
+            .iter()
+            .map(|&i| header[i].raw)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        markup.push_str(&format!(
+            "\n\n\\begin{{affil}}\n{affiliations}\n\\end{{affil}}"
+        ));
+    }
+
+    let first = &header[0];
+    let last = &header[header.len() - 1];
+    format!(
+        "{}{}{}",
+        &text[..first.start],
+        markup,
+        &text[last.start + last.raw.len()..]
+    )
+}
+
+/// Unwraps `{\bfseries ...}` when it spans the whole paragraph
+fn strip_outer_bold(raw: &str) -> &str {
+    let Some(inner) = raw
+        .strip_prefix(r"{\bfseries")
+        .and_then(|rest| rest.strip_suffix('}'))
+    else {
+        return raw;
+    };
+
+    let mut depth = 0i32;
+    for c in inner.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        if depth < 0 {
+            return raw;
+        }
+    }
+    if depth == 0 {
+        inner.trim()
+    } else {
+        raw
+    }
+}
+
+/// Replaces ORCID hyperlinks and icons in an author line with `\alink`
+fn orcid_to_alink(authors: &str) -> String {
+    let re_href =
+        Regex::new(r"\\href\{(https?://orcid\.org/[^}]*)\}\{(?:[^{}]|\{[^{}]*\})*\}").unwrap();
+    let re_url = Regex::new(r"\\url\{(https?://orcid\.org/[^}]*)\}").unwrap();
+    let re_icon = Regex::new(r"\\fig(?:\[[^\]]*\])?\{[^}]*\}\{\}|\\authorid\b\s*").unwrap();
+
+    let authors = re_href.replace_all(authors, r"\alink{$1}");
+    let authors = re_url.replace_all(&authors, r"\alink{$1}");
+    re_icon.replace_all(&authors, r"\alink{}").into_owned()
+}
+
+fn is_email_line(plain: &str) -> bool {
+    let lower = plain.to_lowercase();
+    RE_EMAIL.is_match(plain)
+        || lower.starts_with("e-mail")
+        || lower.starts_with("е-mail")
+        || lower.starts_with("corresponding author")
+}
+
+fn split_authors(raw: &str) -> Vec<String> {
+    // Affiliation numbers are superscripts, drop them before stripping markup
+    let re_sup = Regex::new(r"\\(?:tsp|textsuperscript)\{[^}]*\}").unwrap();
+    let re_sep = Regex::new(r",|;|\s+(?:and|и|және)\s+").unwrap();
+
+    let without_sup = re_sup.replace_all(raw, "");
+    re_sep
+        .split(&strip_latex(&without_sup))
+        .map(strip_markers)
+        .filter(|author| !author.is_empty())
+        .collect()
+}
+
+fn split_keywords(text: &str) -> Vec<String> {
+    text.split([';', ','])
+        .map(|k| k.trim().trim_end_matches('.').trim().to_string())
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+/// Affiliation numbers and the corresponding-author star
+fn is_marker(c: char) -> bool {
+    c.is_whitespace() || c.is_ascii_digit() || "*¹²³⁴⁵⁶⁷⁸⁹⁰,".contains(c)
+}
+
+/// Removes markers around an author name
+fn strip_markers(text: &str) -> String {
+    text.trim_matches(is_marker).to_string()
+}
+
+/// Reduces a LaTeX fragment to its plain text
+pub fn strip_latex(text: &str) -> String {
+    let re_drop =
+        Regex::new(r"\\(?:envelope|authorid)\s*|\\(?:alink|href)\{[^}]*\}|\\fig(?:\[[^\]]*\])?\{[^}]*\}\{[^}]*\}")
+            .unwrap();
+    let re_command = Regex::new(r"\\[a-zA-Z]+\*?\s?").unwrap();
+
+    let text = re_drop.replace_all(text, "");
+    let text = text
+        .replace(r"\_", "_")
+        .replace(r"\&", "&")
+        .replace(r"\%", "%")
+        .replace(r"\#", "#")
+        .replace(r"\$", "$");
+    let text = re_command.replace_all(&text, "");
+    let text = text.replace(['{', '}'], "");
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE: &str = r"\id{МРНТИ 06.81.23}{}
+
+{\bfseries Цифровая экономика Казахстана}
+
+А.Б. Иванов\tsp{1*}, С. Петров\tsp{2}
+
+\tsp{1}Евразийский национальный университет, Астана, Казахстан
+
+E-mail: a.ivanov@enu.kz
+
+Аннотация. Статья о цифровой экономике.
+
+Ключевые слова: экономика; цифровизация, Казахстан.
+
+Текст статьи.
+
+Список литературы
+
+1. Smith J. Digital economy. 2020.
+
+2. Иванов А. Экономика. 2021.
+
+{\bfseries Digital economy of Kazakhstan}
+
+A.B. Ivanov\tsp{1*}, S. Petrov\tsp{2}
+
+Abstract: The article is about the digital economy.
+
+Keywords: economy, digitalization";
+
+    #[test]
+    fn parses_metadata_of_every_title_block() {
+        let article = Article::parse(ARTICLE);
+        assert_eq!(article.irsti.as_deref(), Some("06.81.23"));
+        assert_eq!(article.emails, ["a.ivanov@enu.kz"]);
+        assert_eq!(
+            article.references,
+            [
+                "Smith J. Digital economy. 2020.",
+                "Иванов А. Экономика. 2021."
+            ]
+        );
+
+        let [ru, en] = article.blocks.as_slice() else {
+            panic!("{:?}", article.blocks);
+        };
+        assert_eq!(ru.lang, Lang::Ru);
+        assert_eq!(ru.title, "Цифровая экономика Казахстана");
+        assert_eq!(ru.authors, ["А.Б. Иванов", "С. Петров"]);
+        assert_eq!(
+            ru.affiliations,
+            ["Евразийский национальный университет, Астана, Казахстан"]
+        );
+        assert_eq!(ru.abstract_text, "Статья о цифровой экономике.");
+        assert_eq!(ru.keywords, ["экономика", "цифровизация", "Казахстан"]);
+
+        assert_eq!(en.lang, Lang::En);
+        assert_eq!(en.title, "Digital economy of Kazakhstan");
+        assert_eq!(en.authors, ["A.B. Ivanov", "S. Petrov"]);
+        assert!(en.affiliations.is_empty());
+        assert_eq!(en.keywords, ["economy", "digitalization"]);
+    }
+
+    #[test]
+    fn marker_languages() {
+        assert_eq!(marker_lang("Abstract"), Lang::En);
+        assert_eq!(marker_lang("Резюме"), Lang::Ru);
+        assert_eq!(marker_lang("Аңдатпа"), Lang::Kz);
+        assert_eq!(marker_lang("Түйін сөздер"), Lang::Kz);
+    }
+
+    #[test]
+    fn title_blocks_get_the_journal_markup() {
+        let text = r"{\bfseries Цифровая экономика}
+
+А.Б. Иванов\tsp{1}\href{https://orcid.org/0000-0001}{\authorid}
+
+\tsp{1}ЕНУ, Астана
+
+Аннотация. Текст.";
+        assert_eq!(
+            markup_title_block(text),
+            r"\swa{А.Б. Иванов}{Цифровая экономика}
+
+\begin{header}
+А.Б. Иванов\tsp{1}\alink{https://orcid.org/0000-0001}
+\end{header}
+
+\begin{affil}
+\tsp{1}ЕНУ, Астана
+\end{affil}
+
+Аннотация. Текст."
+        );
+
+        // Marked up once
+        let marked = markup_title_block(text);
+        assert_eq!(markup_title_block(&marked), marked);
+
+        // An ORCID icon sized by fix_images
+        let sized = text.replace(
+            r"\href{https://orcid.org/0000-0001}{\authorid}",
+            r"\fig[0.25\textwidth]{p1/image3}{}",
+        );
+        let marked = markup_title_block(&sized);
+        assert!(
+            marked.starts_with(r"\swa{А.Б. Иванов}{Цифровая экономика}"),
+            "{marked}"
+        );
+        assert!(
+            marked.contains("А.Б. Иванов\\tsp{1}\\alink{}\n\\end{header}"),
+            "{marked}"
+        );
+    }
+}
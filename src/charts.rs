@@ -0,0 +1,526 @@
+use anyhow::Result;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::images::read_zip_entry;
+use crate::util::escape_latex;
+
+/// Colours for pie slices, in the order pgfplots cycles through its own
+const PIE_COLORS: [&str; 8] = [
+    "blue",
+    "red",
+    "brown!60!black",
+    "black",
+    "violet",
+    "cyan",
+    "orange",
+    "green!60!black",
+];
+
+#[derive(Debug, PartialEq)]
+enum ChartKind {
+    /// Vertical (column) or horizontal bars
+    Bar {
+        horizontal: bool,
+    },
+    Line,
+    Area,
+    Scatter,
+    Pie,
+}
+
+#[derive(Debug)]
+struct Series {
+    name: Option<String>,
+    categories: Vec<Option<String>>,
+    /// Scatter x values; categories are used for every other kind
+    x_values: Vec<Option<f64>>,
+    values: Vec<Option<f64>>,
+}
+
+#[derive(Debug)]
+struct Chart {
+    kind: ChartKind,
+    stacked: bool,
+    title: Option<String>,
+    x_label: Option<String>,
+    y_label: Option<String>,
+    legend: bool,
+    series: Vec<Series>,
+}
+
+/// Converts every chart of the DOCX (`word/charts/chartN.xml`) into a
+/// pgfplots picture `chartN.tex` in `output_dir`.
+///
+/// Returns the chart names in document order, which is the order pandoc
+/// emits its `[CHART]` placeholders in; `None` marks a chart that could not
+/// be converted.
+pub fn extract_charts_from_docx<P: AsRef<Path>, Q: AsRef<Path>>(
+    docx_path: P,
+    output_dir: Q,
+) -> Result<Vec<Option<String>>> {
+    let output_dir = output_dir.as_ref();
+    let mut archive = ZipArchive::new(File::open(docx_path)?)?;
+    let document = read_zip_entry(&mut archive, "word/document.xml")?;
+    let rels = read_zip_entry(&mut archive, "word/_rels/document.xml.rels")?;
+
+    // Relationship id -> chart file name without extension
+    let re_rel = Regex::new(r"<Relationship\s[^>]*>")?;
+    let re_id = Regex::new(r#"\bId="([^"]+)""#)?;
+    let re_target = Regex::new(r#"\bTarget="charts/([^"/.]+)\.xml""#)?;
+    let mut targets = HashMap::new();
+    for rel in re_rel.find_iter(&rels) {
+        if let (Some(id), Some(target)) = (
+            re_id.captures(rel.as_str()),
+            re_target.captures(rel.as_str()),
+        ) {
+            targets.insert(id[1].to_string(), target[1].to_string());
+        }
+    }
+
+    let re_chart = Regex::new(r#"<c:chart\b[^>]*\br:id="([^"]+)""#)?;
+    let mut names = Vec::new();
+    for caps in re_chart.captures_iter(&document) {
+        let Some(name) = targets.get(&caps[1]) else {
+            names.push(None);
+            continue;
+        };
+
+        let converted = read_zip_entry(&mut archive, &format!("word/charts/{name}.xml"))
+            .and_then(|xml| parse_chart(&xml))
+            .and_then(|chart| {
+                let tex = render_chart(&chart, name);
+                fs::create_dir_all(output_dir)?;
+                fs::write(output_dir.join(format!("{name}.tex")), tex)?;
+                Ok(())
+            });
+        match converted {
+            Ok(()) => {
+                println!("Converted chart {} to pgfplots", name);
+                names.push(Some(name.clone()));
+            }
+            Err(e) => {
+                eprintln!("Could not convert chart {}: {}", name, e);
+                names.push(None);
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Reads the chart type, series and titles of a DrawingML chart.
+/// Only the first chart group of the plot area is used.
+fn parse_chart(xml: &str) -> Result<Chart> {
+    let re_group = Regex::new(r"(?s)<c:(\w+?)Chart>(.*?)</c:\w+?Chart>")?;
+    let caps = re_group
+        .captures(xml)
+        .ok_or_else(|| anyhow::anyhow!("No chart group found"))?;
+    let group = caps.get(2).unwrap().as_str();
+
+    let kind = match &caps[1] {
+        "bar" | "bar3D" => ChartKind::Bar {
+            horizontal: attribute(group, "c:barDir").as_deref() == Some("bar"),
+        },
+        "line" | "line3D" | "stock" | "radar" => ChartKind::Line,
+        "area" | "area3D" => ChartKind::Area,
+        "scatter" | "bubble" => ChartKind::Scatter,
+        "pie" | "pie3D" | "doughnut" | "ofPie" => ChartKind::Pie,
+        other => anyhow::bail!("Unsupported chart type {}", other),
+    };
+    if re_group.find_iter(xml).count() > 1 {
+        eprintln!("Combined chart, only its first chart type is converted");
+    }
+
+    let stacked = attribute(group, "c:grouping")
+        .is_some_and(|grouping| grouping == "stacked" || grouping == "percentStacked");
+
+    let re_series = Regex::new(r"(?s)<c:ser>(.*?)</c:ser>")?;
+    let series: Vec<Series> = re_series
+        .captures_iter(group)
+        .map(|caps| {
+            let ser = caps.get(1).unwrap().as_str();
+            let (x_tag, y_tag) = match kind {
+                ChartKind::Scatter => ("c:xVal", "c:yVal"),
+                _ => ("c:cat", "c:val"),
+            };
+            Series {
+                name: element(ser, "c:tx").and_then(|tx| points(&tx).into_iter().flatten().next()),
+                categories: element(ser, x_tag).map(|e| points(&e)).unwrap_or_default(),
+                x_values: element(ser, x_tag).map(|e| numbers(&e)).unwrap_or_default(),
+                values: element(ser, y_tag).map(|e| numbers(&e)).unwrap_or_default(),
+            }
+        })
+        .collect();
+    if series.is_empty() {
+        anyhow::bail!("Chart has no data series");
+    }
+
+    // Axis titles go by axis position, so horizontal bars need no special case
+    let re_axis =
+        Regex::new(r"(?s)<c:(?:catAx|valAx|dateAx|serAx)>(.*?)</c:(?:catAx|valAx|dateAx|serAx)>")?;
+    let mut x_label = None;
+    let mut y_label = None;
+    for caps in re_axis.captures_iter(xml) {
+        let axis = caps.get(1).unwrap().as_str();
+        let Some(title) = element(axis, "c:title").map(|t| rich_text(&t)) else {
+            continue;
+        };
+        match attribute(axis, "c:axPos").as_deref() {
+            Some("l") | Some("r") => y_label = Some(title),
+            _ => x_label = Some(title),
+        }
+    }
+
+    // The chart title is the one before the plot area, axis titles are inside it
+    let head = &xml[..xml.find("<c:plotArea>").unwrap_or(xml.len())];
+    let title = element(head, "c:title")
+        .map(|t| rich_text(&t))
+        .filter(|t| !t.is_empty());
+
+    Ok(Chart {
+        kind,
+        stacked,
+        title,
+        x_label: x_label.filter(|t| !t.is_empty()),
+        y_label: y_label.filter(|t| !t.is_empty()),
+        legend: xml.contains("<c:legend>"),
+        series,
+    })
+}
+
+/// Contents of the first `<tag>…</tag>` in `xml`
+fn element(xml: &str, tag: &str) -> Option<String> {
+    let re = Regex::new(&format!(r"(?s)<{tag}>(.*?)</{tag}>")).ok()?;
+    Some(re.captures(xml)?[1].to_string())
+}
+
+/// `val` attribute of the first `<tag val="…"/>` in `xml`
+fn attribute(xml: &str, tag: &str) -> Option<String> {
+    let re = Regex::new(&format!(r#"<{tag}\s+val="([^"]*)""#)).ok()?;
+    Some(re.captures(xml)?[1].to_string())
+}
+
+/// Cached point values of a string or number reference, indexed by `idx`.
+/// Multi-level categories keep their first level.
+fn points(xml: &str) -> Vec<Option<String>> {
+    let re_count = Regex::new(r#"<c:ptCount\s+val="(\d+)""#).unwrap();
+    let re_point = Regex::new(r#"(?s)<c:pt\s+idx="(\d+)"[^>]*>.*?<c:v>(.*?)</c:v>"#).unwrap();
+
+    let count = re_count
+        .captures(xml)
+        .and_then(|caps| caps[1].parse().ok())
+        .unwrap_or(0);
+    let mut values = vec![None; count];
+    for caps in re_point.captures_iter(xml) {
+        let Ok(idx) = caps[1].parse::<usize>() else {
+            continue;
+        };
+        if idx >= values.len() {
+            values.resize(idx + 1, None);
+        }
+        if values[idx].is_none() {
+            values[idx] = Some(decode_xml(&caps[2]));
+        }
+    }
+
+    // A literal series name has no cache, only a bare value
+    if values.is_empty() {
+        if let Some(v) = element(xml, "c:v") {
+            values.push(Some(decode_xml(&v)));
+        }
+    }
+    values
+}
+
+fn numbers(xml: &str) -> Vec<Option<f64>> {
+    points(xml)
+        .into_iter()
+        .map(|p| p.and_then(|p| p.trim().parse().ok()))
+        .collect()
+}
+
+/// Text of the `<a:t>` runs of a title
+fn rich_text(xml: &str) -> String {
+    let re = Regex::new(r"(?s)<a:t>(.*?)</a:t>").unwrap();
+    let text: String = re
+        .captures_iter(xml)
+        .map(|caps| decode_xml(&caps[1]))
+        .collect();
+    text.trim().to_string()
+}
+
+fn decode_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Writes `chart` as a standalone `tikzpicture`. The width is left to the
+/// enclosing `\fig`, which sets it with `\pgfplotsset`.
+fn render_chart(chart: &Chart, name: &str) -> String {
+    let mut out = format!("% Converted from word/charts/{name}.xml\n");
+    if chart.kind == ChartKind::Pie {
+        render_pie(chart, &mut out);
+        return out;
+    }
+
+    let first = &chart.series[0];
+    let count = chart
+        .series
+        .iter()
+        .map(|s| s.values.len())
+        .max()
+        .unwrap_or(0);
+    let labels: Vec<String> = (0..count)
+        .map(|i| match first.categories.get(i) {
+            Some(Some(label)) => format!("{{{}}}", escape_latex(label)),
+            _ => format!("{{{}}}", i + 1),
+        })
+        .collect();
+    let horizontal = chart.kind == (ChartKind::Bar { horizontal: true });
+
+    let mut options = Vec::new();
+    match chart.kind {
+        ChartKind::Bar { horizontal } => {
+            let bar = if horizontal { "xbar" } else { "ybar" };
+            options.push(match chart.stacked {
+                true => format!("{bar} stacked"),
+                false => bar.to_string(),
+            });
+            options.push(format!(
+                "enlarge {} limits=0.15",
+                if horizontal { "y" } else { "x" }
+            ));
+        }
+        ChartKind::Area => {
+            options.push("area style".to_string());
+            if chart.stacked {
+                options.push("stack plots=y".to_string());
+            }
+        }
+        ChartKind::Line if chart.stacked => options.push("stack plots=y".to_string()),
+        _ => {}
+    }
+    if chart.kind != ChartKind::Scatter {
+        let axis = if horizontal { "y" } else { "x" };
+        options.push(format!("{axis}tick=data"));
+        options.push(format!("{axis}ticklabels={{{}}}", labels.join(", ")));
+        if !horizontal && count > 6 {
+            options.push("x tick label style={rotate=45, anchor=east}".to_string());
+        }
+    }
+    if let Some(title) = &chart.title {
+        options.push(format!("title={{{}}}", escape_latex(title)));
+    }
+    if let Some(label) = &chart.x_label {
+        options.push(format!("xlabel={{{}}}", escape_latex(label)));
+    }
+    if let Some(label) = &chart.y_label {
+        options.push(format!("ylabel={{{}}}", escape_latex(label)));
+    }
+    if chart.legend && chart.series.len() > 1 {
+        options.push(
+            "legend style={at={(0.5,-0.15)}, anchor=north, legend columns=-1, draw=none}"
+                .to_string(),
+        );
+    }
+
+    out.push_str("\\begin{tikzpicture}\n\\begin{axis}[\n");
+    for option in &options {
+        let _ = writeln!(out, "    {option},");
+    }
+    out.push_str("]\n");
+
+    for series in &chart.series {
+        let coordinates: Vec<String> = series
+            .values
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| {
+                let value = (*value)?;
+                Some(match chart.kind {
+                    ChartKind::Scatter => {
+                        let x = series
+                            .x_values
+                            .get(i)
+                            .copied()
+                            .flatten()
+                            .unwrap_or(i as f64 + 1.0);
+                        format!("({x},{value})")
+                    }
+                    _ if horizontal => format!("({value},{i})"),
+                    _ => format!("({i},{value})"),
+                })
+            })
+            .collect();
+
+        let style = match chart.kind {
+            ChartKind::Line => "+[mark=*]",
+            ChartKind::Scatter => "+[only marks]",
+            _ => "",
+        };
+        let cycle = if chart.kind == ChartKind::Area {
+            " \\closedcycle"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            out,
+            "\\addplot{style} coordinates {{{}}}{cycle};",
+            coordinates.join(" ")
+        );
+        if chart.legend && chart.series.len() > 1 {
+            if let Some(name) = &series.name {
+                let _ = writeln!(out, "\\addlegendentry{{{}}}", escape_latex(name));
+            }
+        }
+    }
+
+    out.push_str("\\end{axis}\n\\end{tikzpicture}\n");
+    out
+}
+
+/// pgfplots has no pie charts, so the first series is drawn with plain TikZ:
+/// slices clockwise from 12 o'clock as in Word, with the legend on the right
+fn render_pie(chart: &Chart, out: &mut String) {
+    let series = &chart.series[0];
+    let total: f64 = series.values.iter().flatten().filter(|v| **v > 0.0).sum();
+
+    out.push_str("\\begin{tikzpicture}[font=\\small]\n");
+    if let Some(title) = &chart.title {
+        let _ = writeln!(
+            out,
+            "\\node[anchor=south] at (0,2.3) {{{}}};",
+            escape_latex(title)
+        );
+    }
+
+    let mut angle = 90.0;
+    let mut row = 0;
+    for (i, value) in series.values.iter().enumerate() {
+        let Some(value) = value.filter(|v| *v > 0.0 && total > 0.0) else {
+            continue;
+        };
+        let color = PIE_COLORS[row % PIE_COLORS.len()];
+        let sweep = value / total * 360.0;
+        let end = angle - sweep;
+        let middle = (angle + end) / 2.0;
+        let _ = writeln!(
+            out,
+            "\\fill[{color}!70, draw=white] (0,0) -- ({angle:.2}:2) arc[start angle={angle:.2}, end angle={end:.2}, radius=2] -- cycle;"
+        );
+        let _ = writeln!(
+            out,
+            "\\node at ({middle:.2}:1.35) {{{:.0}\\%}};",
+            value / total * 100.0
+        );
+        if let Some(Some(label)) = series.categories.get(i) {
+            let _ = writeln!(
+                out,
+                "\\node[anchor=west] at (2.6,{:.2}) {{\\textcolor{{{color}!70}}{{\\rule{{1ex}}{{1ex}}}} {}}};",
+                1.5 - row as f64 * 0.5,
+                escape_latex(label)
+            );
+        }
+        angle = end;
+        row += 1;
+    }
+
+    out.push_str("\\end{tikzpicture}\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(name: &str, categories: &[&str], values: &[&str]) -> String {
+        let points = |values: &[&str]| {
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, v)| format!(r#"<c:pt idx="{i}"><c:v>{v}</c:v></c:pt>"#))
+                .collect::<String>()
+        };
+        format!(
+            r#"<c:ser><c:tx><c:strRef><c:strCache><c:ptCount val="1"/><c:pt idx="0"><c:v>{name}</c:v></c:pt></c:strCache></c:strRef></c:tx>
+<c:cat><c:strRef><c:strCache><c:ptCount val="{}"/>{}</c:strCache></c:strRef></c:cat>
+<c:val><c:numRef><c:numCache><c:ptCount val="{}"/>{}</c:numCache></c:numRef></c:val></c:ser>"#,
+            categories.len(),
+            points(categories),
+            values.len(),
+            points(values)
+        )
+    }
+
+    fn bar_chart() -> String {
+        format!(
+            r#"<c:chartSpace><c:chart><c:title><c:tx><c:rich><a:p><a:r><a:t>Sales &amp; costs</a:t></a:r></a:p></c:rich></c:tx></c:title>
+<c:plotArea><c:barChart><c:barDir val="col"/><c:grouping val="clustered"/>{}{}</c:barChart>
+<c:catAx><c:axPos val="b"/><c:title><c:tx><c:rich><a:p><a:r><a:t>Year</a:t></a:r></a:p></c:rich></c:tx></c:title></c:catAx>
+<c:valAx><c:axPos val="l"/></c:valAx></c:plotArea><c:legend></c:legend></c:chart></c:chartSpace>"#,
+            series("2023", &["Q1", "Q2"], &["1.5", "2"]),
+            series("2024", &["Q1", "Q2"], &["3", ""])
+        )
+    }
+
+    #[test]
+    fn parses_bar_charts() {
+        let chart = parse_chart(&bar_chart()).unwrap();
+        assert_eq!(chart.kind, ChartKind::Bar { horizontal: false });
+        assert!(!chart.stacked && chart.legend);
+        assert_eq!(chart.title.as_deref(), Some("Sales & costs"));
+        assert_eq!(chart.x_label.as_deref(), Some("Year"));
+        assert_eq!(chart.y_label, None);
+        assert_eq!(chart.series[0].name.as_deref(), Some("2023"));
+        assert_eq!(chart.series[1].values, [Some(3.0), None]);
+    }
+
+    #[test]
+    fn renders_bar_charts_with_pgfplots() {
+        let tex = render_chart(&parse_chart(&bar_chart()).unwrap(), "chart1");
+        assert!(tex.starts_with("% Converted from word/charts/chart1.xml\n\\begin{tikzpicture}"));
+        for line in [
+            "    ybar,",
+            "    xticklabels={{Q1}, {Q2}},",
+            r"    title={Sales \& costs},",
+            "\\addplot coordinates {(0,1.5) (1,2)};",
+            "\\addplot coordinates {(0,3)};",
+            "\\addlegendentry{2024}",
+        ] {
+            assert!(tex.contains(line), "{line} in\n{tex}");
+        }
+    }
+
+    #[test]
+    fn renders_pies_and_rejects_unknown_charts() {
+        let xml = format!(
+            "<c:plotArea><c:pieChart>{}</c:pieChart></c:plotArea>",
+            series("Share", &["A", "B"], &["3", "1"])
+        );
+        let tex = render_chart(&parse_chart(&xml).unwrap(), "chart2");
+        assert!(tex.contains(r"{75\%}") && tex.contains(r"{25\%}"), "{tex}");
+        assert!(tex.contains("} A};") && tex.contains("} B};"));
+
+        let surface = "<c:plotArea><c:surfaceChart></c:surfaceChart></c:plotArea>";
+        assert!(parse_chart(surface).is_err());
+    }
+
+    #[test]
+    fn escapes_latex_specials() {
+        assert_eq!(
+            escape_latex(r"50% of $x_1 & {y}"),
+            r"50\% of \$x\_1 \& \{y\}"
+        );
+        assert_eq!(
+            escape_latex(r"a\b~c^"),
+            r"a\textbackslash{}b\textasciitilde{}c\textasciicircum{}"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::tex_log::{Location, TexLog};
+use crate::util::collect_files;
+
+/// Lines listed per missing glyph
+const MAX_LOCATIONS: usize = 5;
+
+/// Look-alikes of the Kazakh Cyrillic letters, from Latin, IPA or other
+/// Cyrillic alphabets, that Liberation Serif and FreeSans may not have
+const KAZAKH_LOOKALIKES: [(char, char); 18] = [
+    ('ə', 'ә'),
+    ('Ə', 'Ә'),
+    ('ǝ', 'ә'),
+    ('Ǝ', 'Ә'),
+    ('ɵ', 'ө'),
+    ('Ɵ', 'Ө'),
+    ('ҕ', 'ғ'),
+    ('Ҕ', 'Ғ'),
+    ('ҡ', 'қ'),
+    ('Ҡ', 'Қ'),
+    ('ӄ', 'қ'),
+    ('Ӄ', 'Қ'),
+    ('ҥ', 'ң'),
+    ('Ҥ', 'Ң'),
+    ('ӈ', 'ң'),
+    ('Ӈ', 'Ң'),
+    ('ӊ', 'ң'),
+    ('Ӊ', 'Ң'),
+];
+
+/// Symbols better typeset in math mode
+const MATH_SYMBOLS: [(char, &str); 16] = [
+    ('≤', r"$\leq$"),
+    ('≥', r"$\geq$"),
+    ('≠', r"$\neq$"),
+    ('≈', r"$\approx$"),
+    ('∞', r"$\infty$"),
+    ('√', r"$\surd$"),
+    ('∑', r"$\sum$"),
+    ('∆', r"$\Delta$"),
+    ('∂', r"$\partial$"),
+    ('∈', r"$\in$"),
+    ('∉', r"$\notin$"),
+    ('⋅', r"$\cdot$"),
+    ('−', r"$-$"),
+    ('∓', r"$\mp$"),
+    ('→', r"$\rightarrow$"),
+    ('←', r"$\leftarrow$"),
+];
+
+/// A character some font of the document has no glyph for
+#[derive(Debug)]
+pub struct MissingGlyph {
+    pub character: char,
+    pub fonts: Vec<String>,
+    /// Lines containing the character, at most `MAX_LOCATIONS`
+    pub locations: Vec<Location>,
+    pub suggestion: String,
+}
+
+/// Groups the "Missing character" warnings of `log` by character and finds
+/// the lines that contain it, in the file TeX was reading or else anywhere
+/// in main.tex and `src/`
+pub fn find_missing_glyphs(project_dir: &Path, log: &TexLog) -> Vec<MissingGlyph> {
+    let mut glyphs: Vec<MissingGlyph> = Vec::new();
+    let mut files: Vec<Vec<PathBuf>> = Vec::new();
+
+    for missing in &log.missing_characters {
+        let Some(character) = missing.character.chars().next() else {
+            continue;
+        };
+        let i = match glyphs.iter().position(|g| g.character == character) {
+            Some(i) => i,
+            None => {
+                glyphs.push(MissingGlyph {
+                    character,
+                    fonts: Vec::new(),
+                    locations: Vec::new(),
+                    suggestion: suggest_replacement(character),
+                });
+                files.push(Vec::new());
+                glyphs.len() - 1
+            }
+        };
+        if !glyphs[i].fonts.contains(&missing.font) {
+            glyphs[i].fonts.push(missing.font.clone());
+        }
+        if let Some(file) = &missing.location.file {
+            if !files[i].contains(file) {
+                files[i].push(file.clone());
+            }
+        }
+    }
+
+    let mut all_files = vec![PathBuf::from("main.tex")];
+    let mut sources = Vec::new();
+    let _ = collect_files(&project_dir.join("src"), &mut sources);
+    all_files.extend(
+        sources
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "tex"))
+            .filter_map(|path| Some(path.strip_prefix(project_dir).ok()?.to_path_buf())),
+    );
+    all_files.sort();
+
+    for (glyph, files) in glyphs.iter_mut().zip(files) {
+        glyph.locations = find_character(project_dir, &files, glyph.character);
+        if glyph.locations.is_empty() {
+            glyph.locations = find_character(project_dir, &all_files, glyph.character);
+        }
+    }
+    glyphs
+}
+
+/// Lines of `files` containing `character`
+fn find_character(project_dir: &Path, files: &[PathBuf], character: char) -> Vec<Location> {
+    let mut locations = Vec::new();
+    for file in files {
+        let Ok(contents) = read_to_string(project_dir.join(file)) else {
+            continue;
+        };
+        for (i, line) in contents.lines().enumerate() {
+            if line.contains(character) {
+                locations.push(Location {
+                    file: Some(file.clone()),
+                    line: Some(i + 1),
+                });
+                if locations.len() == MAX_LOCATIONS {
+                    return locations;
+                }
+            }
+        }
+    }
+    locations
+}
+
+fn suggest_replacement(character: char) -> String {
+    if let Some((_, letter)) = KAZAKH_LOOKALIKES.iter().find(|(c, _)| *c == character) {
+        return format!(
+            "replace with Cyrillic '{}' (U+{:04X})",
+            letter, *letter as u32
+        );
+    }
+    if let Some((_, math)) = MATH_SYMBOLS.iter().find(|(c, _)| *c == character) {
+        return format!("replace with {}", math);
+    }
+    let font = if is_emoji(character) {
+        "Noto Emoji"
+    } else {
+        "DejaVu Serif"
+    };
+    format!(
+        "use a font that has it, e.g. \\newfontfamily\\fallbackfont{{{}}} and {{\\fallbackfont {}}}",
+        font, character
+    )
+}
+
+fn is_emoji(character: char) -> bool {
+    matches!(character as u32, 0x1F000..=0x1FAFF | 0x2600..=0x27BF)
+}
+
+/// One line per missing glyph: the character, where it is and what to do
+pub fn format_missing_glyphs(glyphs: &[MissingGlyph]) -> String {
+    glyphs
+        .iter()
+        .map(|glyph| {
+            let locations = if glyph.locations.is_empty() {
+                "not found in the sources".to_string()
+            } else {
+                glyph
+                    .locations
+                    .iter()
+                    .map(Location::display)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            format!(
+                "Missing glyph '{}' (U+{:04X}) in {}: {}; {}",
+                glyph.character,
+                glyph.character as u32,
+                glyph.fonts.join(", "),
+                locations,
+                glyph.suggestion
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggestions_by_kind_of_character() {
+        assert_eq!(
+            suggest_replacement('ə'),
+            "replace with Cyrillic 'ә' (U+04D9)"
+        );
+        assert_eq!(suggest_replacement('≤'), r"replace with $\leq$");
+        assert!(suggest_replacement('🖂').contains("{Noto Emoji}"));
+        assert!(suggest_replacement('ǂ').contains(r"{DejaVu Serif} and {\fallbackfont ǂ}"));
+    }
+
+    #[test]
+    fn find_character_lists_lines_up_to_the_limit() {
+        let project = std::env::temp_dir().join(format!("glyphs-{}", std::process::id()));
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::fs::write(project.join("main.tex"), "a\nə\n").unwrap();
+        std::fs::write(project.join("src/001.tex"), "ə\n".repeat(MAX_LOCATIONS)).unwrap();
+
+        let files = [PathBuf::from("main.tex"), PathBuf::from("src/001.tex")];
+        let locations = find_character(&project, &files, 'ə');
+        std::fs::remove_dir_all(&project).unwrap();
+        assert_eq!(locations.len(), MAX_LOCATIONS);
+        assert_eq!(locations[0].display(), "main.tex:2");
+        assert_eq!(locations[1].display(), "src/001.tex:1");
+        assert!(find_character(&project, &files, 'x').is_empty());
+    }
+}
@@ -1,13 +1,230 @@
 use anyhow::Result;
+use image::ImageDecoder;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use zip::ZipArchive;
-// TODO: convert gif, tiff images to png
+
+use crate::manifest::Images;
+
+/// English Metric Units per inch, as used by DrawingML
+const EMU_PER_INCH: f64 = 914_400.0;
+/// Text width assumed when the DOCX has no section properties (A4, 2.5 cm margins)
+const DEFAULT_TEXT_WIDTH_EMU: f64 = 16.0 / 2.54 * EMU_PER_INCH;
+
+/// Placed image sizes read from a DOCX, for deriving `\fig` widths
+#[derive(Debug, Default)]
+pub struct FigureSizing {
+    /// Page width minus left and right margins, in EMU
+    text_width: f64,
+    /// Drawing width (`wp:extent cx`) per media or chart file name without
+    /// extension, in EMU
+    widths: HashMap<String, f64>,
+    /// SVG file name per PNG fallback name, both without extension
+    svgs: HashMap<String, String>,
+    min_width: f64,
+    max_width: f64,
+}
+
+impl FigureSizing {
+    /// Reads drawing extents and the page text width from `word/document.xml`.
+    /// Widths are clamped to `min_width..=max_width` fractions of the text width.
+    pub fn from_docx<P: AsRef<Path>>(docx_path: P, min_width: f64, max_width: f64) -> Result<Self> {
+        let mut archive = ZipArchive::new(File::open(docx_path)?)?;
+        let document = read_zip_entry(&mut archive, "word/document.xml")?;
+        let rels = read_zip_entry(&mut archive, "word/_rels/document.xml.rels")?;
+
+        // Relationship id -> media file name without extension
+        let re_rel = Regex::new(r"<Relationship\s[^>]*>")?;
+        let re_id = Regex::new(r#"\bId="([^"]+)""#)?;
+        let re_target = Regex::new(r#"\bTarget="(?:media|charts)/([^"/.]+)(?:\.[^"]*)?""#)?;
+        let mut targets = HashMap::new();
+        for rel in re_rel.find_iter(&rels) {
+            if let (Some(id), Some(target)) = (
+                re_id.captures(rel.as_str()),
+                re_target.captures(rel.as_str()),
+            ) {
+                targets.insert(id[1].to_string(), target[1].to_string());
+            }
+        }
+
+        let re_drawing = Regex::new(r"(?s)<w:drawing>.*?</w:drawing>")?;
+        let re_extent = Regex::new(r#"<wp:extent\s+cx="(\d+)""#)?;
+        // Pictures embed their media, charts refer to word/charts/chartN.xml
+        let re_embed = Regex::new(r#"r:embed="([^"]+)"|<c:chart\b[^>]*\br:id="([^"]+)""#)?;
+        let re_svg = Regex::new(r#"<asvg:svgBlip\b[^>]*\br:embed="([^"]+)""#)?;
+        let mut widths = HashMap::new();
+        let mut svgs = HashMap::new();
+        for drawing in re_drawing.find_iter(&document) {
+            let drawing = drawing.as_str();
+            let (Some(extent), Some(embed)) =
+                (re_extent.captures(drawing), re_embed.captures(drawing))
+            else {
+                continue;
+            };
+            let id = embed.get(1).or(embed.get(2)).unwrap().as_str();
+            if let Some(name) = targets.get(id) {
+                widths.insert(name.clone(), extent[1].parse::<f64>()?);
+
+                // Word keeps a PNG fallback next to an SVG; the blip embeds the PNG
+                let svg = re_svg
+                    .captures(drawing)
+                    .and_then(|svg| targets.get(&svg[1]));
+                if let Some(svg) = svg {
+                    svgs.insert(name.clone(), svg.clone());
+                }
+            }
+        }
+
+        Ok(FigureSizing {
+            text_width: section_text_width(&document).unwrap_or(DEFAULT_TEXT_WIDTH_EMU),
+            widths,
+            svgs,
+            min_width,
+            max_width,
+        })
+    }
+
+    /// Width of image `name` as a fraction of the text width, from its DOCX
+    /// drawing extent or else from pandoc's `width=` option
+    pub fn fraction(&self, name: &str, options: &str) -> Option<f64> {
+        let width = self
+            .widths
+            .get(name)
+            .copied()
+            .or_else(|| pandoc_width_emu(options))?;
+        if self.text_width <= 0.0 {
+            return None;
+        }
+        Some((width / self.text_width).clamp(self.min_width, self.max_width))
+    }
+
+    /// Name of the image to include for `name`: its SVG when the PNG is a
+    /// fallback for one, otherwise `name` itself
+    pub fn image_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.svgs.get(name).map_or(name, String::as_str)
+    }
+
+    /// Forgets SVGs that are not in `converted`, the names of the vector
+    /// images converted to PDF by this run, so their PNG fallbacks are used
+    pub fn drop_unconverted_svgs(&mut self, converted: &HashSet<String>) {
+        self.svgs.retain(|_, svg| converted.contains(svg));
+    }
+
+    /// PNG fallback and SVG names, both without extension
+    pub fn svg_fallbacks(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.svgs
+            .iter()
+            .map(|(png, svg)| (png.as_str(), svg.as_str()))
+    }
+
+    /// Printed width of image `name` in inches, i.e. its `\fig` width
+    pub fn placed_inches(&self, name: &str) -> Option<f64> {
+        Some(self.fraction(name, "")? * self.text_width / EMU_PER_INCH)
+    }
+}
+
+/// Print-quality audit of one extracted raster image
+#[derive(Debug)]
+pub struct ImageReport {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Placed width in inches, when the DOCX gives one
+    pub placed_inches: Option<f64>,
+    /// Pixels per inch at the placed width, before any downscaling
+    pub dpi: Option<f64>,
+    pub original_bytes: usize,
+    pub bytes: usize,
+    /// What was done to the image, and warnings
+    pub notes: Vec<String>,
+}
+
+impl ImageReport {
+    pub fn is_low_resolution(&self, settings: &Images) -> bool {
+        self.dpi.is_some_and(|dpi| dpi < settings.min_dpi as f64)
+    }
+}
+
+/// Formats the image reports of one part as a plain text table
+pub fn format_image_report(part_name: &str, reports: &[ImageReport]) -> String {
+    let mut out = format!("Images of part {part_name}\n\n");
+    out.push_str(&format!(
+        "{:<16} {:>11} {:>8} {:>6} {:>9} {:>9}  notes\n",
+        "image", "pixels", "width", "dpi", "original", "final"
+    ));
+    for report in reports {
+        out.push_str(&format!(
+            "{:<16} {:>11} {:>8} {:>6} {:>9} {:>9}  {}\n",
+            report.name,
+            format!("{}x{}", report.width, report.height),
+            report
+                .placed_inches
+                .map_or("-".to_string(), |inches| format!("{:.1}cm", inches * 2.54)),
+            report
+                .dpi
+                .map_or("-".to_string(), |dpi| format!("{:.0}", dpi)),
+            format_size(report.original_bytes),
+            format_size(report.bytes),
+            report.notes.join("; ")
+        ));
+    }
+    out
+}
+
+fn format_size(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1}MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{}KB", bytes.div_ceil(1024))
+    }
+}
+
+pub fn read_zip_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut contents = String::new();
+    archive.by_name(name)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Text width of the last section: `w:pgSz w:w` minus the left and right
+/// `w:pgMar`, converted from twentieths of a point to EMU
+fn section_text_width(document: &str) -> Option<f64> {
+    const EMU_PER_TWIP: f64 = 635.0;
+
+    let section = &document[document.rfind("<w:sectPr")?..];
+    let attribute = |element: &str, name: &str| -> Option<f64> {
+        let re = Regex::new(&format!(r#"<{element}\b[^>]*\b{name}="(\d+)""#)).ok()?;
+        re.captures(section)?[1].parse().ok()
+    };
+
+    let page = attribute("w:pgSz", "w:w")?;
+    let left = attribute("w:pgMar", "w:left").unwrap_or(0.0);
+    let right = attribute("w:pgMar", "w:right").unwrap_or(0.0);
+    Some((page - left - right) * EMU_PER_TWIP)
+}
+
+/// Parses `width=3.5in` (or cm, mm, pt) from `\includegraphics` options
+fn pandoc_width_emu(options: &str) -> Option<f64> {
+    let re = Regex::new(r"(?:^|,)\s*width=([0-9.]+)(in|cm|mm|pt)").ok()?;
+    let caps = re.captures(options)?;
+    let value: f64 = caps[1].parse().ok()?;
+    let inches = match &caps[2] {
+        "in" => value,
+        "cm" => value / 2.54,
+        "mm" => value / 25.4,
+        _ => value / 72.27,
+    };
+    Some(inches * EMU_PER_INCH)
+}
 pub fn extract_images_from_docx<P: AsRef<Path>, Q: AsRef<Path>>(
     docx_path: P,
     output_dir: Q,
-) -> Result<()> {
+    settings: &Images,
+    sizing: &FigureSizing,
+) -> Result<(Vec<ImageReport>, HashSet<String>)> {
     let docx_path = docx_path.as_ref();
     let output_dir = output_dir.as_ref();
 
@@ -17,6 +234,10 @@ pub fn extract_images_from_docx<P: AsRef<Path>, Q: AsRef<Path>>(
     // Create output directory if it doesn't exist
     fs::create_dir_all(output_dir)?;
 
+    let mut reports = Vec::new();
+    // Vector images converted to PDF, by name without extension
+    let mut converted = HashSet::new();
+
     // Iterate over files inside the .docx (which is a zip)
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
@@ -29,13 +250,302 @@ pub fn extract_images_from_docx<P: AsRef<Path>, Q: AsRef<Path>>(
                 .ok_or_else(|| anyhow::anyhow!("Invalid filename"))?;
 
             let output_path = output_dir.join(filename);
-            let mut out_file = File::create(&output_path)?;
 
             let mut buffer = Vec::new();
             file.read_to_end(&mut buffer)?;
-            out_file.write_all(&buffer)?;
+
+            let extension = output_path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("")
+                .to_lowercase();
+
+            // XeLaTeX cannot include these, convert them under the same name
+            // so the extension-less \fig paths still resolve
+            let raster_path = match extension.as_str() {
+                "gif" | "tif" | "tiff" | "webp" => convert_raster(&buffer, &output_path)?,
+                "png" | "jpg" | "jpeg" => {
+                    File::create(&output_path)?.write_all(&buffer)?;
+                    Some(output_path)
+                }
+                "wmf" | "emf" | "svg" => {
+                    File::create(&output_path)?.write_all(&buffer)?;
+                    if convert_vector(&output_path, &settings.vector_converter)? {
+                        let stem = output_path.file_stem().unwrap_or_default();
+                        converted.insert(stem.to_string_lossy().to_string());
+                    }
+                    None
+                }
+                _ => {
+                    File::create(&output_path)?.write_all(&buffer)?;
+                    None
+                }
+            };
+
+            if let Some(path) = raster_path {
+                if let Some(report) = optimise_image(&path, buffer.len(), settings, sizing)? {
+                    reports.push(report);
+                }
+            }
         }
     }
 
-    Ok(())
+    Ok((reports, converted))
+}
+
+/// Decodes `data`, applying its EXIF orientation; the flag tells whether the
+/// orientation changed the image
+fn decode_upright(
+    data: &[u8],
+    format: image::ImageFormat,
+) -> image::ImageResult<(image::DynamicImage, bool)> {
+    let mut decoder =
+        image::ImageReader::with_format(std::io::Cursor::new(data), format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = image::DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok((
+        image,
+        orientation != image::metadata::Orientation::NoTransforms,
+    ))
+}
+
+/// Audits the PNG/JPEG at `path` against its placed size, then downscales it
+/// to `target_dpi` if it is above `max_dpi` and converts it to grayscale if
+/// configured. The file is only rewritten when it was rotated, downscaled or
+/// made grayscale.
+fn optimise_image(
+    path: &Path,
+    original_bytes: usize,
+    settings: &Images,
+    sizing: &FigureSizing,
+) -> Result<Option<ImageReport>> {
+    let data = fs::read(path)?;
+    let format = match image::guess_format(&data) {
+        Ok(format @ (image::ImageFormat::Png | image::ImageFormat::Jpeg)) => format,
+        _ => return Ok(None),
+    };
+    let (mut image, rotated) = match decode_upright(&data, format) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            return Ok(None);
+        }
+    };
+
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let placed_inches = sizing.placed_inches(&stem);
+    let dpi = placed_inches.map(|inches| image.width() as f64 / inches);
+    let mut report = ImageReport {
+        name,
+        width: image.width(),
+        height: image.height(),
+        placed_inches,
+        dpi,
+        original_bytes,
+        bytes: data.len(),
+        notes: Vec::new(),
+    };
+
+    if report.is_low_resolution(settings) {
+        report.notes.push(format!(
+            "below {} dpi, too coarse for print",
+            settings.min_dpi
+        ));
+    }
+
+    // xdvipdfmx ignores the EXIF orientation, so phone photos are rotated here
+    let mut modified = rotated;
+    if rotated {
+        report.notes.push("rotated upright".to_string());
+    }
+    if let (Some(dpi), Some(inches)) = (dpi, placed_inches) {
+        if settings.max_dpi > 0 && dpi > settings.max_dpi as f64 {
+            let width = (inches * settings.target_dpi as f64).round().max(1.0) as u32;
+            let height = (image.height() as f64 * width as f64 / image.width() as f64)
+                .round()
+                .max(1.0) as u32;
+            image = image.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+            report
+                .notes
+                .push(format!("downscaled to {}x{}", width, height));
+            modified = true;
+        }
+    }
+    if settings.grayscale && image.color().has_color() {
+        image = if image.color().has_alpha() {
+            image::DynamicImage::ImageLumaA8(image.to_luma_alpha8())
+        } else {
+            image::DynamicImage::ImageLuma8(image.to_luma8())
+        };
+        report.notes.push("converted to grayscale".to_string());
+        modified = true;
+    }
+
+    // Re-encoding an unchanged image would only lose quality and metadata
+    if !modified {
+        return Ok(Some(report));
+    }
+
+    let mut encoded = Vec::new();
+    match format {
+        image::ImageFormat::Jpeg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut encoded,
+                settings.jpeg_quality,
+            );
+            // JPEG has no alpha channel
+            if image.color().has_color() {
+                image.to_rgb8().write_with_encoder(encoder)?;
+            } else {
+                image.to_luma8().write_with_encoder(encoder)?;
+            }
+        }
+        _ => image.write_to(&mut std::io::Cursor::new(&mut encoded), format)?,
+    }
+
+    fs::write(path, &encoded)?;
+    report.bytes = encoded.len();
+
+    Ok(Some(report))
+}
+
+/// Decodes a GIF, TIFF or WebP image and saves it as PNG next to `path`,
+/// returning the PNG path. The original is written instead if it cannot be
+/// decoded.
+fn convert_raster(buffer: &[u8], path: &Path) -> Result<Option<PathBuf>> {
+    let png_path = path.with_extension("png");
+    match image::load_from_memory(buffer) {
+        Ok(image) => {
+            image.save_with_format(&png_path, image::ImageFormat::Png)?;
+            println!(
+                "Converted {} to {}",
+                path.display(),
+                png_path.file_name().unwrap().to_string_lossy()
+            );
+            Ok(Some(png_path))
+        }
+        Err(e) => {
+            eprintln!("Could not convert {} to PNG: {}", path.display(), e);
+            File::create(path)?.write_all(buffer)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Runs the external `converter` command to turn a WMF/EMF/SVG file into a PDF
+/// next to it. `{input}` and `{output}` in the command are replaced by the
+/// paths. The original is kept if the conversion fails. Returns whether the
+/// PDF was written.
+fn convert_vector(path: &Path, converter: &str) -> Result<bool> {
+    let pdf_path = path.with_extension("pdf");
+    // A PDF left by an earlier run must not pass for this run's output
+    if pdf_path.exists() {
+        fs::remove_file(&pdf_path)?;
+    }
+    let input = path.to_string_lossy();
+    let output = pdf_path.to_string_lossy();
+
+    let mut args = converter
+        .split_whitespace()
+        .map(|arg| arg.replace("{input}", &input).replace("{output}", &output));
+    let program = args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Empty vector image converter command"))?;
+
+    match Command::new(&program).args(args).output() {
+        Ok(result) if result.status.success() && pdf_path.exists() => {
+            fs::remove_file(path)?;
+            println!(
+                "Converted {} to {}",
+                path.display(),
+                pdf_path.file_name().unwrap().to_string_lossy()
+            );
+            return Ok(true);
+        }
+        Ok(result) => eprintln!(
+            "Could not convert {} with '{}' ({}): {}",
+            path.display(),
+            program,
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ),
+        Err(e) => eprintln!(
+            "Could not convert {} with '{}': {}",
+            path.display(),
+            program,
+            e
+        ),
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x2 JPEG with an EXIF orientation; 6 is "rotate 90° clockwise"
+    fn jpeg_with_orientation(orientation: u8) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        image::RgbImage::new(4, 2)
+            .write_with_encoder(image::codecs::jpeg::JpegEncoder::new(&mut jpeg))
+            .unwrap();
+
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0");
+        exif.extend_from_slice(&[orientation, 0, 0, 0, 0, 0, 0]);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(&exif);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn applies_exif_orientation() {
+        let (image, rotated) =
+            decode_upright(&jpeg_with_orientation(6), image::ImageFormat::Jpeg).unwrap();
+        assert!(rotated);
+        assert_eq!((image.width(), image.height()), (2, 4));
+    }
+
+    #[test]
+    fn only_changed_images_are_rewritten() {
+        let dir = std::env::temp_dir().join(format!("optimise-image-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Images::default();
+        let sizing = FigureSizing::default();
+
+        let upright = dir.join("upright.jpg");
+        let jpeg = jpeg_with_orientation(1);
+        fs::write(&upright, &jpeg).unwrap();
+        let report = optimise_image(&upright, jpeg.len(), &settings, &sizing).unwrap();
+        assert!(report.unwrap().notes.is_empty());
+        assert_eq!(fs::read(&upright).unwrap(), jpeg);
+
+        let rotated = dir.join("rotated.jpg");
+        fs::write(&rotated, jpeg_with_orientation(6)).unwrap();
+        let report = optimise_image(&rotated, 0, &settings, &sizing)
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.notes, ["rotated upright"]);
+        assert_ne!(fs::read(&rotated).unwrap(), jpeg_with_orientation(6));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leftover_pdfs_are_not_conversions() {
+        let dir = std::env::temp_dir().join(format!("convert-vector-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let svg = dir.join("image2.svg");
+        fs::write(&svg, "<svg/>").unwrap();
+        fs::write(dir.join("image2.pdf"), "stale").unwrap();
+
+        let failed = convert_vector(&svg, "false {input} {output}").unwrap();
+        assert!(!failed && svg.exists() && !dir.join("image2.pdf").exists());
+        let converted = convert_vector(&svg, "cp {input} {output}").unwrap();
+        assert!(converted && !svg.exists() && dir.join("image2.pdf").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
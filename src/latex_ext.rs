@@ -1,19 +1,32 @@
+use crate::article::strip_latex;
+use crate::images::FigureSizing;
 use regex::Regex;
 
+use std::sync::LazyLock;
+
 pub trait LatexStringExt {
     fn replace_textbf(&mut self);
     fn remove_short_bfseries(&mut self) -> Result<(), regex::Error>;
     fn fix_lists(&mut self);
     fn fix_number_spacing(&mut self) -> Result<(), regex::Error>;
-    fn remove_tag(&mut self, tag: &str);
-    fn comment_out_tables(&mut self);
+    fn unwrap_tag(&mut self, tag: &str) -> usize;
+    fn convert_tables(&mut self);
     fn change_latex_quotes(&mut self);
     fn replace_envelopes(&mut self);
     fn remove_tightlists(&mut self);
+    fn remove_labelenumi(&mut self);
     fn unindent(&mut self);
     fn split_articles(&self) -> Vec<String>;
     fn replace_bullets(&mut self);
-    fn fix_images(&mut self, part_name: &str);
+    fn group_images(&mut self, part_name: &str, sizing: &FigureSizing);
+    fn fix_images(&mut self, part_name: &str, sizing: &FigureSizing);
+    fn insert_charts(
+        &mut self,
+        part_name: &str,
+        charts: &[Option<String>],
+        sizing: &FigureSizing,
+    ) -> usize;
+    fn attach_figure_captions(&mut self);
     fn replace_super_sub_scripts(&mut self);
     fn fix_email_links(&mut self);
 	fn remove_zero_hspace(&mut self);
@@ -48,10 +61,6 @@ impl LatexStringExt for String {
         for line in self.lines() {
             let trimmed_line = line.trim_start();
 
-            if trimmed_line == r"\def\labelenumi{\arabic{enumi}.}" {
-                continue;
-            }
-
             if trimmed_line.contains(r"\begin{itemize}") {
                 list_state = ListState::Itemize;
                 continue;
@@ -137,59 +146,57 @@ impl LatexStringExt for String {
         Ok(())
     }
 
-    fn remove_tag(&mut self, tag: &str) {
-        let mut output = String::new();
-        let chars: Vec<char> = self.chars().collect();
-        let tag_pattern = format!("\\{}{{", tag);
-        let tag_chars: Vec<char> = tag_pattern.chars().collect();
-
-        let mut i = 0;
-        while i < chars.len() {
-            if chars[i..].starts_with(&tag_chars) {
-                i += tag_chars.len();
-                let mut brace_level = 1;
-                while i < chars.len() {
-                    match chars[i] {
-                        '{' => brace_level += 1,
-                        '}' => {
-                            brace_level -= 1;
-                            i += 1;
-                            if brace_level == 0 {
-                                break;
-                            }
-                            continue;
-                        }
-                        _ => {}
-                    }
-                    i += 1;
-                }
-            } else {
-                output.push(chars[i]);
-                i += 1;
-            }
-        }
-
+    fn unwrap_tag(&mut self, tag: &str) -> usize {
+        let (output, count) = unwrap_command(self, tag);
         *self = output;
+        count
     }
 
-    fn comment_out_tables(&mut self) {
-        let mut inside_table = false;
-        let mut result = Vec::new();
+    fn convert_tables(&mut self) {
+        let re_caption = Regex::new(r"^(?:Таблица|Кесте|Table)\s*\d+(?:\.\d+)*\s*[–—-]").unwrap();
+
+        let mut result: Vec<String> = Vec::new();
+        let mut table: Option<Vec<&str>> = None;
 
         for line in self.lines() {
-            if line.starts_with(r"\begin{longtable}[]{@{}") {
-                inside_table = true;
-                result.push(format!("%% {}", line));
-            } else if line.starts_with(r"\end{longtable}") && inside_table {
-                inside_table = false;
-                result.push(format!("%% {}", line));
-            } else if inside_table {
-                result.push(format!("%% {}", line));
+            if let Some(lines) = table.as_mut() {
+                lines.push(line);
+                if line.trim_start().starts_with(r"\end{longtable}") {
+                    let lines = table.take().unwrap();
+
+                    // The caption is the paragraph right above the table
+                    while result.last().is_some_and(|l| l.trim().is_empty()) {
+                        result.pop();
+                    }
+                    // Matched without markup, as the label alone may be bold
+                    let caption = result
+                        .last()
+                        .filter(|l| re_caption.is_match(&strip_latex(l)))
+                        .map(|l| unwrap_caption(l));
+                    if caption.is_some() {
+                        result.pop();
+                        while result.last().is_some_and(|l| l.trim().is_empty()) {
+                            result.pop();
+                        }
+                    }
+                    if !result.is_empty() {
+                        result.push(String::new());
+                    }
+
+                    result.push(longtable_to_longtblr(&lines.join("\n"), caption));
+                }
+            } else if line.trim_start().starts_with(r"\begin{longtable}") {
+                table = Some(vec![line]);
             } else {
                 result.push(line.to_string());
             }
         }
 
+        // Unterminated table: keep pandoc's output untouched
+        if let Some(lines) = table {
+            result.extend(lines.into_iter().map(str::to_string));
+        }
+
         *self = result.join("\n");
     }
 
@@ -203,6 +210,8 @@ impl LatexStringExt for String {
         // First: replace envelope emoji with LaTeX command
         *self = self.replace('🖂', r"\envelope ");
         *self = self.replace(r"\textsuperscript{\envelope }", r"\envelope ");
+        // The AST pass writes superscripts as \tsp already
+        *self = self.replace(r"\tsp{\envelope }", r"\envelope ");
         *self = self.replace(r"{\bfseries \envelope }", r"\envelope ");
     }
 
@@ -210,6 +219,14 @@ impl LatexStringExt for String {
         *self = self.replace(r"\tightlist", "");
     }
 
+    fn remove_labelenumi(&mut self) {
+        *self = self
+            .lines()
+            .filter(|line| line.trim_start() != r"\def\labelenumi{\arabic{enumi}.}")
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
     fn unindent(&mut self) {
         *self = self
             .lines()
@@ -251,14 +268,198 @@ impl LatexStringExt for String {
         articles
     }
 
-    fn fix_images(&mut self, part_name: &str) {
-        let re = Regex::new(
-            r"\\includegraphics\[[^]]*\]\{media/([^}/\\]+?)(?:\.(?:png|jpe?g|pdf|webp|wmf|emf|gif))?\}",
-        )
-        .unwrap();
+    fn group_images(&mut self, part_name: &str, sizing: &FigureSizing) {
+        // Layout tables holding images side by side
+        let mut result: Vec<String> = Vec::new();
+        let mut table: Option<Vec<&str>> = None;
+
+        for line in self.lines() {
+            if let Some(lines) = table.as_mut() {
+                lines.push(line);
+                if line.trim_start().starts_with(r"\end{longtable}") {
+                    let lines = table.take().unwrap();
+                    let source = lines.join("\n");
+                    match image_table_rows(&source) {
+                        Some(rows) => result.push(render_figure_group(&rows, part_name, sizing)),
+                        None => result.push(source),
+                    }
+                }
+            } else if line.trim_start().starts_with(r"\begin{longtable}") {
+                table = Some(vec![line]);
+            } else {
+                result.push(line.to_string());
+            }
+        }
+        if let Some(lines) = table {
+            result.extend(lines.into_iter().map(str::to_string));
+        }
+
+        // Paragraphs holding several images and nothing but spacing
+        let re_spacing = Regex::new(r"\\(?:hfill|quad|qquad|hspace\{[^}]*\}|,)|~").unwrap();
+        let re_label = Regex::new(r"(?:^|\s)\(?[а-яәіңғүұқөһa-z]\)\s").unwrap();
+
+        let mut paragraphs: Vec<Option<String>> = result
+            .join("\n")
+            .split("\n\n")
+            .map(|p| Some(p.to_string()))
+            .collect();
+
+        for i in 0..paragraphs.len() {
+            let Some(paragraph) = paragraphs[i].clone() else {
+                continue;
+            };
+            let images: Vec<String> = RE_INCLUDEGRAPHICS
+                .find_iter(&paragraph)
+                .map(|m| m.as_str().to_string())
+                .collect();
+            if images.len() < 2 {
+                continue;
+            }
+            let rest = RE_INCLUDEGRAPHICS.replace_all(&paragraph, "");
+            if !re_spacing.replace_all(&rest, "").trim().is_empty() {
+                continue;
+            }
+
+            // Sub-captions come as "a) ... b) ..." in the paragraph below
+            let mut captions = vec![String::new(); images.len()];
+            if let Some(next) = paragraphs.get(i + 1).cloned().flatten() {
+                let next = next.trim();
+                let starts: Vec<usize> = re_label
+                    .find_iter(next)
+                    .map(|m| m.start() + (m.as_str().len() - m.as_str().trim_start().len()))
+                    .collect();
+                if starts.len() == images.len() && starts[0] == 0 {
+                    for (n, start) in starts.iter().enumerate() {
+                        let end = starts.get(n + 1).copied().unwrap_or(next.len());
+                        captions[n] = next[*start..end].trim().to_string();
+                    }
+                    paragraphs[i + 1] = None;
+                }
+            }
+
+            let row = images
+                .into_iter()
+                .zip(captions)
+                .map(|(image, caption)| SubFigure { image, caption })
+                .collect();
+            paragraphs[i] = Some(render_figure_group(&[row], part_name, sizing));
+        }
+
+        *self = paragraphs
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n\n");
+    }
+
+    fn fix_images(&mut self, part_name: &str, sizing: &FigureSizing) {
+        *self = RE_INCLUDEGRAPHICS
+            .replace_all(self, |caps: &regex::Captures| {
+                let name = &caps["name"];
+                let options = caps.name("options").map_or("", |m| m.as_str());
+                let width = sizing.fraction(name, options);
+                let name = sizing.image_name(name);
+                match width {
+                    Some(width) => {
+                        format!("\\fig[{width:.2}\\textwidth]{{{part_name}/{name}}}{{}}")
+                    }
+                    None => format!("\\fig{{{part_name}/{name}}}{{}}"),
+                }
+            })
+            .into();
+    }
+
+    /// Replaces pandoc's `[CHART]` placeholders, in order, with `\fig`s of the
+    /// converted charts. Placeholders of charts that failed to convert are kept.
+    fn insert_charts(
+        &mut self,
+        part_name: &str,
+        charts: &[Option<String>],
+        sizing: &FigureSizing,
+    ) -> usize {
+        let re = Regex::new(r"\{\[\}CHART\{\]\}|\[CHART\]").unwrap();
+        let mut charts = charts.iter();
+        let mut inserted = 0;
         *self = re
-            .replace_all(self, format!("\\fig{{{part_name}/$1}}{{}}"))
+            .replace_all(self, |caps: &regex::Captures| match charts.next() {
+                Some(Some(name)) => {
+                    inserted += 1;
+                    match sizing.fraction(name, "") {
+                        Some(width) => {
+                            format!("\\fig[{width:.2}\\textwidth]{{{part_name}/{name}}}{{}}")
+                        }
+                        None => format!("\\fig{{{part_name}/{name}}}{{}}"),
+                    }
+                }
+                _ => caps[0].to_string(),
+            })
             .into();
+        inserted
+    }
+
+    fn attach_figure_captions(&mut self) {
+        // A single \fig, or a \figstart ... \figend group, still without caption
+        let re_fig =
+            Regex::new(r"(?s)^\\fig(?:\[[^]]*\])?\{[^}]*\}\{\}$|^\\figstart\{\}\n.*\\figend$")
+                .unwrap();
+        let re_caption =
+            Regex::new(r"^(?:Рис(?:унок)?\.?|Сурет|Figure|Fig\.)\s*\d+(?:\.\d+)*\s*[–—.-]")
+                .unwrap();
+
+        let mut paragraphs: Vec<Option<String>> =
+            self.split("\n\n").map(|p| Some(p.to_string())).collect();
+
+        // Matched without markup, as the label alone may be bold
+        let is_caption = |p: &Option<String>| {
+            p.as_ref()
+                .is_some_and(|p| re_caption.is_match(&strip_latex(p)))
+        };
+        let next_text = |paragraphs: &[Option<String>], from: usize, step: isize| {
+            let mut j = from as isize + step;
+            while j >= 0 && (j as usize) < paragraphs.len() {
+                match &paragraphs[j as usize] {
+                    Some(p) if !p.trim().is_empty() => return Some(j as usize),
+                    _ => j += step,
+                }
+            }
+            None
+        };
+
+        for i in 0..paragraphs.len() {
+            let Some(fig) = paragraphs[i].as_ref().map(|p| p.trim().to_string()) else {
+                continue;
+            };
+            if !re_fig.is_match(&fig) {
+                continue;
+            }
+
+            // Captions go below the figure; fall back to the paragraph above
+            // unless it belongs to a figure of its own
+            let below = next_text(&paragraphs, i, 1).filter(|&j| is_caption(&paragraphs[j]));
+            let above = next_text(&paragraphs, i, -1)
+                .filter(|&j| is_caption(&paragraphs[j]))
+                .filter(|&j| {
+                    next_text(&paragraphs, j, -1).is_none_or(|k| {
+                        !paragraphs[k]
+                            .as_ref()
+                            .is_some_and(|p| re_fig.is_match(p.trim()))
+                    })
+                });
+
+            if let Some(j) = below.or(above) {
+                let caption = unwrap_caption(&paragraphs[j].take().unwrap());
+                paragraphs[i] = Some(match fig.strip_prefix(r"\figstart{}") {
+                    Some(group) => format!("\\figstart{{{}}}{}", caption, group),
+                    None => format!("{}{{{}}}", &fig[..fig.len() - 2], caption),
+                });
+            }
+        }
+
+        *self = paragraphs
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n\n");
     }
 
     fn replace_super_sub_scripts(&mut self) {
@@ -294,3 +495,651 @@ impl LatexStringExt for String {
 		*self = self.replace(r"\textless", "<");
 	}
 }
+
+/// Returns the contents of the brace group `s` starts with, and the rest
+fn read_group(s: &str) -> Option<(&str, &str)> {
+    let s = s.strip_prefix('{')?;
+    let mut depth = 1;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&s[..i], &s[i + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Unwraps the bold of a caption paragraph, whether it spans the whole
+/// paragraph or only its leading "Рис. 1" label
+fn unwrap_caption(s: &str) -> String {
+    let s = s.trim();
+    let whole = unwrap_bold(s);
+    if whole.len() != s.len() {
+        return whole.to_string();
+    }
+    let label = s
+        .strip_prefix(r"{\bfseries")
+        .map(|rest| format!("{{{rest}"));
+    match label.as_deref().and_then(read_group) {
+        Some((label, rest)) => format!("{}{}", label.trim(), rest),
+        None => s.to_string(),
+    }
+}
+
+/// Unwraps `{\bfseries ...}` when it spans the whole string
+fn unwrap_bold(s: &str) -> &str {
+    match s
+        .strip_prefix(r"{\bfseries")
+        .map(|rest| format!("{{{rest}"))
+    {
+        Some(group) => match read_group(&group) {
+            Some((inner, "")) => s[s.len() - inner.len() - 1..s.len() - 1].trim(),
+            _ => s,
+        },
+        None => s,
+    }
+}
+
+/// Splits `s` at every top-level occurrence of `separator`, ignoring
+/// separators nested in braces or minipages
+fn split_top_level<'a>(s: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut minipages = 0;
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < s.len() {
+        let rest = &s[i..];
+        if rest.starts_with(r"\begin{minipage}") {
+            minipages += 1;
+        } else if rest.starts_with(r"\end{minipage}") {
+            minipages -= 1;
+        }
+
+        if depth == 0 && minipages == 0 && rest.starts_with(separator) {
+            parts.push(&s[start..i]);
+            i += separator.len();
+            start = i;
+            continue;
+        }
+
+        let c = rest.chars().next().unwrap();
+        match c {
+            '\\' => {
+                // Skip escaped characters such as \& \{ \}, but not a \\ separator
+                let next = rest[1..].chars().next();
+                if next.is_some_and(|n| n != '\\' || separator != r"\\") {
+                    i += 1 + next.unwrap().len_utf8();
+                    continue;
+                }
+            }
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Translates a pandoc column spec such as `@{}ll@{}` or
+/// `>{\raggedright\arraybackslash}p{(\linewidth - 4\tabcolsep) * \real{0.5}}`
+/// into tabularray `X` columns
+fn longtable_colspec(spec: &str) -> String {
+    let re_column = Regex::new(
+        r"(?s)>\{\\(raggedright|centering|raggedleft)\\arraybackslash\}\s*p\{[^{}]*\\real\{([0-9.]+)\}\}|([lcr])",
+    )
+    .unwrap();
+    let spec = spec
+        .trim()
+        .trim_start_matches("@{}")
+        .trim_end_matches("@{}");
+
+    re_column
+        .captures_iter(spec)
+        .map(|caps| {
+            let align = match caps.get(1).or(caps.get(3)).map_or("l", |m| m.as_str()) {
+                "centering" | "c" => "c",
+                "raggedleft" | "r" => "r",
+                _ => "l",
+            };
+            match caps.get(2) {
+                Some(width) => format!("X[{},{}]", width.as_str(), align),
+                None => format!("X[{}]", align),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Unwraps pandoc's minipage and alignment commands around a cell and
+/// returns its paragraphs, each on one line
+fn cell_paragraphs(cell: &str) -> Vec<String> {
+    let re_minipage =
+        Regex::new(r"(?s)\\begin\{minipage\}(?:\[[^]]*\])?\{[^}]*\}(.*?)\\end\{minipage\}")
+            .unwrap();
+    let re_align = Regex::new(r"^\\(?:raggedright|raggedleft|centering)\s*").unwrap();
+
+    let text = re_minipage.replace_all(cell.trim(), "$1");
+    let text = re_align.replace(text.trim(), "");
+
+    text.split("\n\n")
+        .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Converts one longtable cell, returning its text and how many columns it spans
+fn convert_cell(cell: &str) -> (String, usize) {
+    let re_multicolumn = Regex::new(r"^\\multicolumn\{(\d+)\}").unwrap();
+    let re_multirow = Regex::new(r"^\\multirow(?:\[[^]]*\])?\{(\d+)\}\{[^}]*\}").unwrap();
+
+    let mut cell = cell.trim();
+    let mut columns = 1;
+    let mut rows = 1;
+    let mut align = None;
+
+    if let Some(caps) = re_multicolumn.captures(cell) {
+        let rest = &cell[caps[0].len()..];
+        if let Some((spec, rest)) = read_group(rest) {
+            if let Some((content, _)) = read_group(rest.trim_start()) {
+                columns = caps[1].parse().unwrap_or(1);
+                // "X[l]" or "X[0.5,l]": the alignment is the letter before ']'
+                align = longtable_colspec(spec)
+                    .trim_end_matches(']')
+                    .chars()
+                    .last()
+                    .filter(|c| "lcr".contains(*c));
+                cell = content.trim();
+            }
+        }
+    }
+
+    if let Some(caps) = re_multirow.captures(cell) {
+        if let Some((content, _)) = read_group(&cell[caps[0].len()..]) {
+            rows = caps[1].parse().unwrap_or(1);
+            cell = content.trim();
+        }
+    }
+
+    // Paragraphs become line breaks, which tabularray wants inside braces
+    let paragraphs = cell_paragraphs(cell);
+    let mut text = if paragraphs.len() > 1 {
+        format!("{{{}}}", paragraphs.join(r"\\"))
+    } else {
+        paragraphs.concat()
+    };
+
+    if columns > 1 || rows > 1 {
+        let mut span = Vec::new();
+        if rows > 1 {
+            span.push(format!("r={rows}"));
+        }
+        if columns > 1 {
+            span.push(format!("c={columns}"));
+        }
+        text = format!(
+            r"\SetCell[{}]{{{}}} {}",
+            span.join(","),
+            align.map(String::from).unwrap_or_default(),
+            text
+        );
+    }
+
+    (text, columns)
+}
+
+/// Converts the rows of a longtable section into tabularray rows
+fn convert_rows(section: &str) -> Vec<String> {
+    let re_rule =
+        Regex::new(r"\\(?:toprule|midrule|bottomrule|hline)(?:\\noalign\{\})?|\\noalign\{\}")
+            .unwrap();
+    let section = re_rule
+        .replace_all(section, "")
+        .replace(r"\tabularnewline", r"\\");
+
+    split_top_level(&section, r"\\")
+        .into_iter()
+        .filter(|row| !row.trim().is_empty())
+        .map(|row| {
+            let mut cells = Vec::new();
+            for cell in split_top_level(row, "&") {
+                let (text, columns) = convert_cell(cell);
+                cells.push(text);
+                // Spanned columns still need their (empty) cells in tabularray
+                cells.extend((1..columns).map(|_| String::new()));
+            }
+            format!("{} \\\\", cells.join(" & "))
+        })
+        .collect()
+}
+
+/// Rebuilds pandoc's `longtable` output as a tabularray `longtblr`
+fn longtable_to_longtblr(table: &str, caption: Option<String>) -> String {
+    let re_begin = Regex::new(r"^\s*\\begin\{longtable\}(?:\[[^]]*\])?").unwrap();
+    let re_end = Regex::new(r"\\end\{longtable\}\s*$").unwrap();
+    let re_marker = Regex::new(r"\\end(firsthead|head|foot|lastfoot)\b").unwrap();
+    let re_table_caption = Regex::new(r"\\caption\{").unwrap();
+
+    let Some(begin) = re_begin.find(table) else {
+        return table.to_string();
+    };
+    let Some((spec, body)) = read_group(&table[begin.end()..]) else {
+        return table.to_string();
+    };
+    let body = re_end.replace(body, "");
+
+    // Header rows come before the first \endfirsthead/\endhead, body rows
+    // after the last marker; repeated headers and footers are dropped
+    let markers: Vec<_> = re_marker.find_iter(&body).collect();
+    let (mut head, rows) = match (markers.first(), markers.last()) {
+        (Some(first), Some(last)) if ["\\endfirsthead", "\\endhead"].contains(&first.as_str()) => {
+            (body[..first.start()].to_string(), &body[last.end()..])
+        }
+        (Some(_), Some(last)) => (String::new(), &body[last.end()..]),
+        _ => (String::new(), &body[..]),
+    };
+
+    // A caption pandoc put inside the table is used if none was found above it
+    let mut caption = caption;
+    if let Some(m) = re_table_caption.find(&head) {
+        if let Some((text, rest)) = read_group(&head[m.end() - 1..]) {
+            caption.get_or_insert_with(|| text.trim().to_string());
+            head = format!("{}{}", &head[..m.start()], rest);
+        }
+    }
+
+    let head_rows = convert_rows(&head);
+    let body_rows = convert_rows(rows);
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "\\begin{{longtblr}}[\ncaption = {{{}}},\nentry = none,\nlabel = none,\n]{{\n",
+        caption.unwrap_or_default()
+    ));
+    out.push_str(&format!("colspec = {{{}}},\n", longtable_colspec(spec)));
+    out.push_str("hlines, vlines,\n");
+    if !head_rows.is_empty() {
+        out.push_str(&format!(
+            "rowhead = {},\nrow{{1-{}}} = {{font=\\bfseries}},\n",
+            head_rows.len(),
+            head_rows.len()
+        ));
+    }
+    out.push_str("}\n");
+    for row in head_rows.iter().chain(&body_rows) {
+        out.push_str(row);
+        out.push('\n');
+    }
+    out.push_str("\\end{longtblr}");
+    out
+}
+
+/// Drops every `\tag{...}` command but keeps its argument.
+/// Returns the new text and the number of commands unwrapped.
+fn unwrap_command(text: &str, tag: &str) -> (String, usize) {
+    let mut output = String::new();
+    let chars: Vec<char> = text.chars().collect();
+    let tag_pattern = format!("\\{}{{", tag);
+    let tag_chars: Vec<char> = tag_pattern.chars().collect();
+    let mut count = 0;
+
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i..].starts_with(&tag_chars) {
+            count += 1;
+            i += tag_chars.len();
+            let mut brace_level = 1;
+            while i < chars.len() {
+                match chars[i] {
+                    '\\' if i + 1 < chars.len() => {
+                        // Escaped braces do not change the level
+                        output.push(chars[i]);
+                        output.push(chars[i + 1]);
+                        i += 2;
+                        continue;
+                    }
+                    '{' => brace_level += 1,
+                    '}' => {
+                        brace_level -= 1;
+                        if brace_level == 0 {
+                            i += 1;
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                output.push(chars[i]);
+                i += 1;
+            }
+        } else {
+            output.push(chars[i]);
+            i += 1;
+        }
+    }
+
+    (output, count)
+}
+
+/// Matches pandoc's `\includegraphics[OPTIONS]{media/NAME.ext}`, capturing
+/// `options` and `name`
+static RE_INCLUDEGRAPHICS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\\includegraphics(?:\[(?P<options>[^]]*)\])?\{media/(?P<name>[^}/\\]+?)(?:\.(?:png|jpe?g|pdf|webp|wmf|emf|gif|tiff?))?\}",
+    )
+    .unwrap()
+});
+
+/// One image of a figure group and the text below it
+struct SubFigure {
+    /// The original `\includegraphics` command
+    image: String,
+    caption: String,
+}
+
+/// Reads a longtable used to lay out images, returning the image rows with
+/// the text under each image, or `None` if it is a regular table
+fn image_table_rows(table: &str) -> Option<Vec<Vec<SubFigure>>> {
+    const MAX_CAPTION_LENGTH: usize = 300;
+
+    let re_begin = Regex::new(r"^\s*\\begin\{longtable\}(?:\[[^]]*\])?").unwrap();
+    let re_end = Regex::new(r"\\end\{longtable\}\s*$").unwrap();
+    let re_repeated_head = Regex::new(r"(?s)\\endfirsthead.*?\\endhead").unwrap();
+    let re_markup = Regex::new(
+        r"\\end(?:firsthead|head|foot|lastfoot)\b|\\(?:toprule|midrule|bottomrule|hline)(?:\\noalign\{\})?|\\noalign\{\}|\\caption\{[^}]*\}",
+    )
+    .unwrap();
+
+    let begin = re_begin.find(table)?;
+    let (_, body) = read_group(&table[begin.end()..])?;
+    let body = re_end.replace(body, "");
+    let body = re_repeated_head.replace(&body, "");
+    let body = re_markup
+        .replace_all(&body, "")
+        .replace(r"\tabularnewline", r"\\");
+
+    let rows: Vec<Vec<String>> = split_top_level(&body, r"\\")
+        .into_iter()
+        .filter(|row| !row.trim().is_empty())
+        .map(|row| {
+            split_top_level(row, "&")
+                .into_iter()
+                .map(|cell| cell_paragraphs(cell).join(" "))
+                .collect()
+        })
+        .collect();
+
+    let has_images = |row: &Vec<String>| row.iter().any(|c| RE_INCLUDEGRAPHICS.is_match(c));
+    if !rows.iter().any(has_images) {
+        return None;
+    }
+
+    let mut groups = Vec::new();
+    let mut i = 0;
+    while i < rows.len() {
+        if !has_images(&rows[i]) {
+            // Text rows are only allowed as captions right under images
+            return None;
+        }
+        let captions_below = rows.get(i + 1).filter(|row| !has_images(row));
+
+        let mut group = Vec::new();
+        for (c, cell) in rows[i].iter().enumerate() {
+            let images: Vec<&str> = RE_INCLUDEGRAPHICS
+                .find_iter(cell)
+                .map(|m| m.as_str())
+                .collect();
+            let mut caption = RE_INCLUDEGRAPHICS.replace_all(cell, "").trim().to_string();
+            if caption.is_empty() {
+                caption = captions_below
+                    .and_then(|row| row.get(c))
+                    .map(|c| c.trim().to_string())
+                    .unwrap_or_default();
+            }
+            if caption.chars().count() > MAX_CAPTION_LENGTH {
+                return None;
+            }
+            for image in &images {
+                group.push(SubFigure {
+                    image: image.to_string(),
+                    caption: if images.len() == 1 {
+                        caption.clone()
+                    } else {
+                        String::new()
+                    },
+                });
+            }
+        }
+        groups.push(group);
+        i += if captions_below.is_some() { 2 } else { 1 };
+    }
+
+    Some(groups)
+}
+
+/// Renders image rows as `\figstart{}` / `\subfig` / `\figend`, or a single
+/// image as a plain paragraph for `fix_images`
+fn render_figure_group(rows: &[Vec<SubFigure>], part_name: &str, sizing: &FigureSizing) -> String {
+    const GROUP_WIDTH: f64 = 0.9;
+    const SUBFIG_HEIGHT: &str = r"0.3\textheight";
+
+    if let [row] = rows {
+        if let [single] = row.as_slice() {
+            return if single.caption.is_empty() {
+                single.image.clone()
+            } else {
+                format!("{}\n\n{}", single.image, single.caption)
+            };
+        }
+    }
+
+    let mut lines = vec![r"\figstart{}".to_string()];
+    for (r, row) in rows.iter().enumerate() {
+        if r > 0 {
+            lines.push(r"\par\medskip".to_string());
+        }
+        let width = GROUP_WIDTH / row.len() as f64;
+        for (n, figure) in row.iter().enumerate() {
+            let path = RE_INCLUDEGRAPHICS.replace(&figure.image, |caps: &regex::Captures| {
+                format!("{part_name}/{}", sizing.image_name(&caps["name"]))
+            });
+            let separator = if n + 1 < row.len() { r"\hfill" } else { "" };
+            lines.push(format!(
+                "\\subfig[{width:.2}\\textwidth]{{{SUBFIG_HEIGHT}}}{{{path}}}{{{}}}{separator}",
+                figure.caption
+            ));
+        }
+    }
+    lines.push(r"\figend".to_string());
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelopes_lose_their_superscript() {
+        for input in [r"A\tsp{🖂}", r"A\textsuperscript{🖂}", r"A{\bfseries 🖂}"] {
+            let mut text = input.to_string();
+            text.replace_envelopes();
+            assert_eq!(text, r"A\envelope ", "{}", input);
+        }
+    }
+
+    #[test]
+    fn tags_are_unwrapped_with_their_contents() {
+        let mut text = r"\label{intro} \ul{key {\bfseries word} \{}".to_string();
+        assert_eq!(text.unwrap_tag("ul"), 1);
+        assert_eq!(text, r"\label{intro} key {\bfseries word} \{");
+    }
+
+    const LONGTABLE: &str = r"\begin{longtable}[]{@{}
+  >{\raggedright\arraybackslash}p{(\linewidth - 2\tabcolsep) * \real{0.3000}}
+  >{\centering\arraybackslash}p{(\linewidth - 2\tabcolsep) * \real{0.7000}}@{}}
+\toprule\noalign{}
+Name & Value \\
+\midrule\noalign{}
+\endhead
+\bottomrule\noalign{}
+\endlastfoot
+\multicolumn{2}{@{}l@{}}{Both} \\
+a & \multirow{1}{*}{b} \\
+\end{longtable}";
+
+    #[test]
+    fn longtables_become_longtblr_with_their_caption() {
+        let mut text =
+            format!("Text\n\n{{\\bfseries Таблица 1 – Results}}\n\n{LONGTABLE}\n\nAfter");
+        text.convert_tables();
+        assert_eq!(
+            text,
+            r"Text
+
+\begin{longtblr}[
+caption = {Таблица 1 – Results},
+entry = none,
+label = none,
+]{
+colspec = {X[0.3000,l] X[0.7000,c]},
+hlines, vlines,
+rowhead = 1,
+row{1-1} = {font=\bfseries},
+}
+Name & Value \\
+\SetCell[c=2]{l} Both &  \\
+a & b \\
+\end{longtblr}
+
+After"
+        );
+
+        // Only the label is bold
+        let mut text = format!("{{\\bfseries Кесте 2}} – Нәтижелер\n\n{LONGTABLE}");
+        text.convert_tables();
+        assert!(
+            text.starts_with("\\begin{longtblr}[\ncaption = {Кесте 2 – Нәтижелер},"),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn table_captions_come_from_above_or_inside_the_table() {
+        let inside = LONGTABLE.replace(
+            r"\toprule",
+            r"\caption{Table 2 - Inside}\tabularnewline\toprule",
+        );
+        let mut text = inside.clone();
+        text.convert_tables();
+        assert!(text.contains("caption = {Table 2 - Inside},"), "{}", text);
+        assert!(!text.contains(r"\caption"));
+
+        // A paragraph that is not a table label stays a paragraph
+        let mut text = format!("Some results\n\n{inside}");
+        text.convert_tables();
+        assert!(text.starts_with("Some results\n\n\\begin{longtblr}"));
+        assert!(text.contains("caption = {Table 2 - Inside},"));
+    }
+
+    fn attach(text: &str) -> String {
+        let mut text = text.to_string();
+        text.attach_figure_captions();
+        text
+    }
+
+    #[test]
+    fn figure_captions_below_or_above() {
+        assert_eq!(
+            attach("\\fig{p1/image1}{}\n\nРис. 1 – Схема\n\nText"),
+            "\\fig{p1/image1}{Рис. 1 – Схема}\n\nText"
+        );
+        assert_eq!(
+            attach("Text\n\n{\\bfseries Figure 2 – Plan}\n\n\\fig[0.5\\textwidth]{p1/image2}{}\n\nMore"),
+            "Text\n\n\\fig[0.5\\textwidth]{p1/image2}{Figure 2 – Plan}\n\nMore"
+        );
+        // The caption below a figure is not taken by the next one
+        assert_eq!(
+            attach("\\fig{p1/a}{}\n\nFig. 1. A\n\n\\fig{p1/b}{}"),
+            "\\fig{p1/a}{Fig. 1. A}\n\n\\fig{p1/b}{}"
+        );
+    }
+
+    #[test]
+    fn figure_captions_of_groups_and_bold_labels() {
+        let group = "\\figstart{}\n\\subfig[0.48\\textwidth]{5cm}{p1/a}{а) A}\\hfill\n\\subfig[0.48\\textwidth]{5cm}{p1/b}{б) B}\n\\figend";
+        assert_eq!(
+            attach(&format!("{group}\n\nСурет 3 – Топ")),
+            group.replace("\\figstart{}", "\\figstart{Сурет 3 – Топ}")
+        );
+        assert_eq!(
+            attach("\\fig{p1/image4}{}\n\n{\\bfseries Рис. 4} – Карта {\\bfseries региона}"),
+            "\\fig{p1/image4}{Рис. 4 – Карта {\\bfseries региона}}"
+        );
+    }
+
+    fn group(text: &str) -> String {
+        let mut text = text.to_string();
+        text.group_images("p1", &FigureSizing::default());
+        text
+    }
+
+    fn table(rows: &str) -> String {
+        format!("\\begin{{longtable}}[]{{@{{}}ll@{{}}}}\n\\toprule\\noalign{{}}\n{rows}\n\\bottomrule\\noalign{{}}\n\\end{{longtable}}")
+    }
+
+    #[test]
+    fn image_paragraphs_become_figure_groups() {
+        let text = group(
+            "Text\n\n\\includegraphics[width=2in]{media/image1.png}\\hfill\\includegraphics{media/image2.jpeg}\n\nа) Карта б) План\n\nAfter",
+        );
+        assert_eq!(
+            text,
+            r"Text
+
+\figstart{}
+\subfig[0.45\textwidth]{0.3\textheight}{p1/image1}{а) Карта}\hfill
+\subfig[0.45\textwidth]{0.3\textheight}{p1/image2}{б) План}
+\figend
+
+After"
+        );
+    }
+
+    #[test]
+    fn layout_tables_become_figure_groups() {
+        let text = group(&table(
+            "\\includegraphics{media/image1.png} & \\includegraphics{media/image2.png} \\\\\nа) Карта & б) План \\\\",
+        ));
+        assert_eq!(
+            text,
+            r"\figstart{}
+\subfig[0.45\textwidth]{0.3\textheight}{p1/image1}{а) Карта}\hfill
+\subfig[0.45\textwidth]{0.3\textheight}{p1/image2}{б) План}
+\figend"
+        );
+
+        // One image is a plain paragraph, with its caption below
+        let text = group(&table(
+            "\\includegraphics{media/image3.png} & \\\\\nLogo & \\\\",
+        ));
+        assert_eq!(text, "\\includegraphics{media/image3.png}\n\nLogo");
+    }
+
+    #[test]
+    fn data_tables_with_images_stay_tables() {
+        let source = table("Name & Photo \\\\\n\\midrule\\noalign{}\nIvanov & \\includegraphics{media/image4.png} \\\\");
+        assert_eq!(group(&source), source);
+    }
+}
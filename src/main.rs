@@ -1,15 +1,31 @@
+mod article;
+mod charts;
+mod glyphs;
 mod images;
 mod latex_ext;
+mod manifest;
+mod media;
+mod pandoc_ast;
 mod pandoc_ext;
 mod project;
+mod tex_log;
 mod util;
+use colored::*;
 use project::*;
 
 use std::env;
 use std::error::Error;
+use std::io::{self, Write};
 use std::path::PathBuf;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", format!("Error: {}", e).red());
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
 
     // Show brief usage for no arguments
@@ -21,7 +37,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Show detailed help for help flag
     if args
         .get(1)
-        .map_or(false, |arg| arg == "--help" || arg == "-h")
+        .is_some_and(|arg| arg == "--help" || arg == "-h")
     {
         print_usage();
         return Ok(());
@@ -32,8 +48,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     match action {
         "create" | "update" => handle_create_update(&args)?,
         "compile" => handle_compile(&args)?,
+        "build" => handle_build(&args)?,
+        "media" => handle_media(&args)?,
+        "rebuild" => handle_rebuild(&args)?,
         _ => {
-            eprintln!("Error: Unknown action '{}'. Available actions: create, update, compile. Use --help for more information.", action);
+            eprintln!("Error: Unknown action '{}'. Available actions: create, update, rebuild, compile, build, media. Use --help for more information.", action);
             std::process::exit(1);
         }
     }
@@ -51,16 +70,39 @@ USAGE:
 ACTIONS:
     create <project_dir> <docx_file>    Create new project from DOCX
     update <project_dir> <docx_file>    Update existing project with DOCX
+    rebuild [project_dir]               Re-import every part from its DOCX copy
+                                        in src/<part>/, e.g. after an upgrade
     compile [project_dir]               Compile project (watch mode)
+    build [project_dir]                 Compile project once; exits non-zero on
+                                        failure and prints the PDF path
+    media [project_dir] [--prune]       Report unused media files and figures
+                                        pointing to missing files
 
 EXAMPLES:
     make-vestnik create ./my-project document.docx
+    make-vestnik create ./my-project document.docx --template ./my-template
     make-vestnik update ./my-project updated.docx
+    make-vestnik rebuild ./my-project
     make-vestnik compile ./my-project
     make-vestnik compile                          # Uses current directory
+    make-vestnik build ./my-project 2>build.log   # Prints the PDF path
+    make-vestnik media ./my-project --prune
 
 OPTIONS:
-    -h, --help                          Show this help message"
+    --template <dir>                    Create the project from <dir> instead of
+                                        the built-in template
+    --prune                             Delete the unused media files found by
+                                        'media', after asking
+    -h, --help                          Show this help message
+
+FILES:
+    .cache/pandoc/                      pandoc outputs, keyed by input hash,
+                                        pandoc version and arguments; safe to
+                                        delete
+
+ENVIRONMENT:
+    MAKE_VESTNIK_TEMPLATE               Template directory used by 'create' when
+                                        --template is not given"
     );
 }
 
@@ -76,17 +118,22 @@ fn handle_create_update(args: &[String]) -> Result<(), Box<dyn Error>> {
     // Parse arguments to identify docx file and project directory (order doesn't matter)
     let mut docx_file = None;
     let mut project_dir = None;
+    let mut template_dir = None;
 
-    for arg in &args[2..] {
-        if arg.ends_with(".docx") {
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--template" {
+            let dir = rest.next().ok_or("--template requires a directory")?;
+            template_dir = Some(PathBuf::from(dir));
+        } else if arg.ends_with(".docx") {
             docx_file = Some(arg);
         } else {
             project_dir = Some(arg);
         }
     }
 
-    let docx_file = docx_file.ok_or("Error: Missing .docx file")?;
-    let project_dir = project_dir.ok_or("Error: Missing project directory")?;
+    let docx_file = docx_file.ok_or("Missing .docx file")?;
+    let project_dir = project_dir.ok_or("Missing project directory")?;
 
     // Check if DOCX file exists
     if !PathBuf::from(docx_file).exists() {
@@ -101,7 +148,7 @@ fn handle_create_update(args: &[String]) -> Result<(), Box<dyn Error>> {
 
     if action == "create" {
         println!("Creating new project...");
-        create_project(project_dir)?;
+        create_project(project_dir, template_dir.as_deref())?;
         println!("Project created successfully.");
     }
 
@@ -148,3 +195,115 @@ fn handle_compile(args: &[String]) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn handle_media(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let prune = args[2..].iter().any(|arg| arg == "--prune");
+    let project_dir = match args[2..].iter().find(|arg| !arg.starts_with("--")) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            env::current_dir().map_err(|e| format!("Failed to get current directory: {}", e))?
+        }
+    };
+    if !project_dir.join("main.tex").exists() {
+        eprintln!(
+            "Error: '{}' is not a project directory (no main.tex).",
+            project_dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    let audit = media::audit_media(&project_dir)?;
+
+    for reference in &audit.dangling {
+        eprintln!(
+            "{}",
+            format!(
+                "{}:{}: missing image '{}'",
+                reference.file.display(),
+                reference.line,
+                reference.path
+            )
+            .red()
+        );
+    }
+    for orphan in &audit.orphans {
+        let path = orphan.strip_prefix(&project_dir).unwrap_or(orphan);
+        println!("Unused: {}", path.display());
+    }
+    println!(
+        "{} unused media files, {} missing images",
+        audit.orphans.len(),
+        audit.dangling.len()
+    );
+
+    if !audit.orphans.is_empty() {
+        if prune {
+            print!(
+                "Delete the {} files listed above? [y/N] ",
+                audit.orphans.len()
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if answer.trim().eq_ignore_ascii_case("y") {
+                media::prune_orphans(&audit)?;
+                println!("Deleted {} unused media files.", audit.orphans.len());
+            } else {
+                println!("Nothing deleted.");
+            }
+        } else {
+            println!("Run with --prune to delete the unused media files.");
+        }
+    }
+
+    if !audit.dangling.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+fn handle_rebuild(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let project_dir = match args.get(2) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            env::current_dir().map_err(|e| format!("Failed to get current directory: {}", e))?
+        }
+    };
+    if !project_dir.join("main.tex").exists() {
+        eprintln!(
+            "Error: '{}' is not a project directory (no main.tex).",
+            project_dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    let parts = rebuild_project(&project_dir)?;
+    println!("Rebuilt {} parts.", parts);
+
+    Ok(())
+}
+
+fn handle_build(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let project_dir = match args.get(2) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            env::current_dir().map_err(|e| format!("Failed to get current directory: {}", e))?
+        }
+    };
+    if !project_dir.join("main.tex").exists() {
+        eprintln!(
+            "Error: '{}' is not a project directory (no main.tex).",
+            project_dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    if !build_project(&project_dir)? {
+        std::process::exit(1);
+    }
+    // The only line on stdout, for scripts
+    println!("{}", project_dir.join("build").join("main.pdf").display());
+
+    Ok(())
+}
@@ -0,0 +1,323 @@
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use std::error::Error;
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use crate::util::{escape_latex, write_if_changed};
+
+pub const MANIFEST_FILE: &str = "vestnik.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub issue: Issue,
+    pub journal: Journal,
+    pub staff: Staff,
+    pub publisher: Publisher,
+    #[serde(default)]
+    pub figures: Figures,
+    #[serde(default)]
+    pub images: Images,
+    #[serde(default)]
+    pub build: Build,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Issue {
+    pub number: u32,
+    /// Running number of the issue since the journal was founded
+    pub total: u32,
+    pub year: u32,
+    pub sign_date: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub running_head: String,
+    pub issn_print: String,
+    pub issn_online: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Staff {
+    pub editor: String,
+    pub layout: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Publisher {
+    pub address: String,
+    pub phone: String,
+    pub email: String,
+}
+
+/// Import settings for figures
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Figures {
+    /// Smallest `\fig` width, as a fraction of the text width
+    pub min_width: f64,
+    /// Largest `\fig` width, as a fraction of the text width
+    pub max_width: f64,
+}
+
+impl Default for Figures {
+    fn default() -> Self {
+        Figures {
+            min_width: 0.25,
+            max_width: 1.0,
+        }
+    }
+}
+
+/// Settings for images extracted from the DOCX
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Images {
+    /// Command converting WMF, EMF and SVG to PDF; `{input}` and `{output}` are
+    /// replaced by the file paths
+    pub vector_converter: String,
+    /// Images printed below this resolution are reported as too coarse
+    pub min_dpi: u32,
+    /// Images above this resolution are downscaled to `target_dpi`; 0 keeps
+    /// them as they are
+    pub max_dpi: u32,
+    pub target_dpi: u32,
+    /// Quality of JPEGs rewritten after rotating, downscaling or grayscale
+    /// conversion, 1-100
+    pub jpeg_quality: u8,
+    /// Convert images to grayscale, for the black-and-white print edition
+    pub grayscale: bool,
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Images {
+            vector_converter: "inkscape --export-type=pdf --export-filename={output} {input}"
+                .to_string(),
+            min_dpi: 150,
+            max_dpi: 600,
+            target_dpi: 300,
+            jpeg_quality: 85,
+            grayscale: false,
+        }
+    }
+}
+
+/// How `compile` and `build` run TeX
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Build {
+    pub engine: Engine,
+    pub driver: Driver,
+    /// Extra arguments, passed before `main.tex` to the engine or to latexmk
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Xelatex,
+    Lualatex,
+    /// Fetches missing packages itself and reruns until the output settles
+    Tectonic,
+}
+
+impl Engine {
+    pub fn program(self) -> &'static str {
+        match self {
+            Engine::Xelatex => "xelatex",
+            Engine::Lualatex => "lualatex",
+            Engine::Tectonic => "tectonic",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// Run the engine ourselves, again until the auxiliary files settle
+    #[default]
+    Direct,
+    /// Leave the reruns to latexmk
+    Latexmk,
+}
+
+impl Manifest {
+    /// Loads `vestnik.toml` from the project root, `None` if the project has none.
+    pub fn load(project_dir: &Path) -> Result<Option<Self>, Box<dyn Error>> {
+        let path = project_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = read_to_string(&path)?;
+        let manifest: Manifest =
+            toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        manifest
+            .validate()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some(manifest))
+    }
+
+    /// Checks the values serde cannot, naming the offending key
+    fn validate(&self) -> Result<(), String> {
+        let figures = &self.figures;
+        for (key, width) in [
+            ("figures.min_width", figures.min_width),
+            ("figures.max_width", figures.max_width),
+        ] {
+            if !(width.is_finite() && width > 0.0) {
+                return Err(format!("{} must be a positive number, not {}", key, width));
+            }
+        }
+        if figures.min_width > figures.max_width {
+            return Err(format!(
+                "figures.min_width ({}) is larger than figures.max_width ({})",
+                figures.min_width, figures.max_width
+            ));
+        }
+
+        let images = &self.images;
+        if images.target_dpi == 0 {
+            return Err("images.target_dpi must be larger than 0".to_string());
+        }
+        if images.max_dpi > 0 && images.target_dpi > images.max_dpi {
+            return Err(format!(
+                "images.target_dpi ({}) is larger than images.max_dpi ({})",
+                images.target_dpi, images.max_dpi
+            ));
+        }
+        if !(1..=100).contains(&images.jpeg_quality) {
+            return Err(format!(
+                "images.jpeg_quality must be between 1 and 100, not {}",
+                images.jpeg_quality
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replaces every `{{section.key}}` in `template` with the manifest value.
+    /// Strings are plain text, escaped for LaTeX.
+    pub fn render(&self, template: &str) -> Result<String, Box<dyn Error>> {
+        let values = toml::Value::try_from(self)?;
+        let re = Regex::new(r"\{\{\s*([a-z_]+)\.([a-z_]+)\s*\}\}")?;
+
+        let mut missing = Vec::new();
+        let rendered = re.replace_all(template, |caps: &Captures| {
+            match values
+                .get(&caps[1])
+                .and_then(|section| section.get(&caps[2]))
+            {
+                Some(toml::Value::String(s)) => escape_latex(s),
+                Some(value) => value.to_string(),
+                None => {
+                    missing.push(caps[0].to_string());
+                    String::new()
+                }
+            }
+        });
+
+        if !missing.is_empty() {
+            return Err(format!("Unknown manifest keys: {}", missing.join(", ")).into());
+        }
+        Ok(rendered.into_owned())
+    }
+}
+
+/// Renders every `src/*.tex.in` of the project into the `.tex` next to it.
+/// Projects without a manifest are left untouched.
+pub fn render_templates(project_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let Some(manifest) = Manifest::load(project_dir)? else {
+        return Ok(());
+    };
+
+    for entry in read_dir(project_dir.join("src"))? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let Some(target) = name.strip_suffix(".in") else {
+            continue;
+        };
+
+        let template = read_to_string(&path)?;
+        let rendered = manifest
+            .render(&template)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        write_if_changed(path.with_file_name(target), rendered)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUIRED: &str = r#"
+[issue]
+number = 1
+total = 1
+year = 2025
+sign_date = "01.01.2025"
+[journal]
+running_head = "Vestnik"
+issn_print = "0000-0000"
+issn_online = "0000-0000"
+[staff]
+editor = "A"
+layout = "B"
+[publisher]
+address = "C"
+phone = "D"
+email = "E"
+"#;
+
+    fn validate(extra: &str) -> Result<(), String> {
+        let manifest: Manifest = toml::from_str(&format!("{}{}", REQUIRED, extra)).unwrap();
+        manifest.validate()
+    }
+
+    #[test]
+    fn accepts_defaults() {
+        assert_eq!(validate(""), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_figure_widths() {
+        let err = validate("[figures]\nmin_width = 0.8\nmax_width = 0.5\n").unwrap_err();
+        assert!(err.contains("figures.min_width"), "{}", err);
+        let err = validate("[figures]\nmax_width = nan\n").unwrap_err();
+        assert!(err.contains("figures.max_width"), "{}", err);
+        let err = validate("[figures]\nmin_width = 0.0\n").unwrap_err();
+        assert!(err.contains("figures.min_width"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_bad_image_settings() {
+        let err = validate("[images]\ntarget_dpi = 0\n").unwrap_err();
+        assert!(err.contains("images.target_dpi"), "{}", err);
+        let err = validate("[images]\nmax_dpi = 300\ntarget_dpi = 600\n").unwrap_err();
+        assert!(err.contains("images.target_dpi"), "{}", err);
+        assert_eq!(
+            validate("[images]\nmax_dpi = 0\ntarget_dpi = 600\n"),
+            Ok(())
+        );
+        let err = validate("[images]\njpeg_quality = 0\n").unwrap_err();
+        assert!(err.contains("images.jpeg_quality"), "{}", err);
+    }
+
+    #[test]
+    fn renders_strings_escaped() {
+        let manifest: Manifest =
+            toml::from_str(&REQUIRED.replace(r#"address = "C""#, r#"address = "R&D_1, 100% #2""#))
+                .unwrap();
+        let rendered = manifest
+            .render("{{publisher.address}} - {{ issue.year }}")
+            .unwrap();
+        assert_eq!(rendered, r"R\&D\_1, 100\% \#2 - 2025");
+        assert!(manifest.render("{{issue.missing}}").is_err());
+    }
+}
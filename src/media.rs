@@ -0,0 +1,461 @@
+use regex::Regex;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{create_dir_all, read, read_dir, read_to_string, remove_file, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::images::FigureSizing;
+use crate::project::TEMPLATE;
+use crate::util::{collect_files, content_hash, write_if_changed};
+
+/// Extensions tried for an image path given without one, in graphicx order.
+/// `.tex` is a chart that `\fig` inputs instead of including.
+const IMAGE_EXTENSIONS: [&str; 5] = ["pdf", "png", "jpg", "jpeg", "tex"];
+
+/// Extensions of the vector images `update` converts to a PDF of the same name
+const VECTOR_EXTENSIONS: [&str; 3] = ["svg", "wmf", "emf"];
+
+/// Directory in `media/` holding images used by more than one figure
+pub const SHARED_DIR: &str = "shared";
+
+/// An image path in a `.tex` file that does not resolve to a file
+#[derive(Debug)]
+pub struct DanglingReference {
+    pub file: PathBuf,
+    pub line: usize,
+    pub path: String,
+}
+
+/// Result of cross-referencing the image paths of a project with `media/`
+#[derive(Debug, Default)]
+pub struct MediaAudit {
+    /// Files in `media/` that no `.tex` file refers to
+    pub orphans: Vec<PathBuf>,
+    pub dangling: Vec<DanglingReference>,
+}
+
+/// Collects every `\fig`, `\subfig`, `\includegraphics` and `\includepdf` path
+/// in main.tex, `src/**/*.tex` and the `*.tex.in` templates, and compares them
+/// with the files in `media/`
+pub fn audit_media<P: AsRef<Path>>(project_dir: P) -> Result<MediaAudit, Box<dyn Error>> {
+    let project_dir = project_dir.as_ref();
+
+    // \fig and \subfig paths are relative to media/, the others to the project
+    let re_fig = Regex::new(r"\\fig(?:\[[^\]]*\])?\{([^}]*)\}")?;
+    let re_subfig = Regex::new(r"\\subfig(?:\[[^\]]*\])?\{[^}]*\}\{([^}]*)\}")?;
+    let re_include = Regex::new(r"\\include(?:graphics|pdf)(?:\[[^\]]*\])?\{([^}]*)\}")?;
+
+    let mut tex_files = vec![project_dir.join("main.tex")];
+    collect_files(&project_dir.join("src"), &mut tex_files)?;
+    tex_files.retain(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        (name.ends_with(".tex") || name.ends_with(".tex.in")) && path.is_file()
+    });
+
+    let mut sources = Vec::new();
+    for file in &tex_files {
+        let name = file.strip_prefix(project_dir).unwrap_or(file).to_path_buf();
+        sources.push((Some(name), read_to_string(file)?));
+    }
+    // The embedded template may not be rendered into the project yet. Its
+    // images are used, but a project without them has no missing images.
+    for file in TEMPLATE
+        .get_dir("src")
+        .into_iter()
+        .flat_map(|dir| dir.files())
+    {
+        if file.path().to_string_lossy().ends_with(".tex.in") {
+            sources.push((None, file.contents_utf8().unwrap_or_default().to_string()));
+        }
+    }
+
+    let mut audit = MediaAudit::default();
+    let mut used = HashSet::new();
+    for (file, contents) in &sources {
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line);
+            let paths = re_fig
+                .captures_iter(line)
+                .chain(re_subfig.captures_iter(line))
+                .map(|caps| format!("media/{}", &caps[1]))
+                .chain(
+                    re_include
+                        .captures_iter(line)
+                        .map(|caps| caps[1].to_string()),
+                );
+
+            for path in paths {
+                // Parameters of command definitions, e.g. media/#2
+                if path.contains('#') {
+                    continue;
+                }
+                match (resolve(project_dir, &path), file) {
+                    (Some(resolved), _) => {
+                        used.insert(resolved);
+                    }
+                    (None, Some(file)) => audit.dangling.push(DanglingReference {
+                        file: file.clone(),
+                        line: i + 1,
+                        path,
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+
+    // An SVG and its PNG fallback, or a vector image and the PDF it was
+    // converted to, are used together
+    let used_names: HashSet<(PathBuf, String)> =
+        used.iter().filter_map(|path| file_name(path)).collect();
+    let partners = svg_partners(project_dir)?;
+    let is_fallback = |path: &Path| {
+        let Some((dir, stem)) = file_name(path) else {
+            return false;
+        };
+        let is_vector = path.extension().is_some_and(|ext| {
+            VECTOR_EXTENSIONS
+                .iter()
+                .any(|v| ext.eq_ignore_ascii_case(v))
+        });
+        (is_vector && used_names.contains(&(dir.clone(), stem.clone())))
+            || partners
+                .get(&(dir.clone(), stem))
+                .is_some_and(|partner| used_names.contains(&(dir, partner.clone())))
+    };
+
+    let mut media_files = Vec::new();
+    collect_files(&project_dir.join("media"), &mut media_files)?;
+    audit.orphans = media_files
+        .into_iter()
+        .filter(|path| !used.contains(path) && !is_fallback(path))
+        .collect();
+    audit.orphans.sort();
+
+    Ok(audit)
+}
+
+/// The SVG of each PNG fallback and the other way round, by `media/<part>`
+/// directory and name without extension, from the DOCX copy of each part
+fn svg_partners(project_dir: &Path) -> Result<HashMap<(PathBuf, String), String>, Box<dyn Error>> {
+    let mut partners = HashMap::new();
+    let media_dir = project_dir.join("media");
+    if !media_dir.is_dir() {
+        return Ok(partners);
+    }
+    for entry in read_dir(&media_dir)? {
+        let dir = entry?.path();
+        let Some(part) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let docx = project_dir
+            .join("src")
+            .join(part)
+            .join(format!("{part}.docx"));
+        if !dir.is_dir() || !docx.is_file() {
+            continue;
+        }
+        let sizing = FigureSizing::from_docx(&docx, 0.0, 1.0)?;
+        for (png, svg) in sizing.svg_fallbacks() {
+            partners.insert((dir.clone(), png.to_string()), svg.to_string());
+            partners.insert((dir.clone(), svg.to_string()), png.to_string());
+        }
+    }
+    Ok(partners)
+}
+
+/// Directory and name without extension of `path`
+fn file_name(path: &Path) -> Option<(PathBuf, String)> {
+    Some((
+        path.parent()?.to_path_buf(),
+        path.file_stem()?.to_string_lossy().to_string(),
+    ))
+}
+
+/// Deletes the orphaned media files found by `audit_media`
+pub fn prune_orphans(audit: &MediaAudit) -> io::Result<()> {
+    for path in &audit.orphans {
+        remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Moves images of `part_name` that have the same contents as another image
+/// of the project into `media/shared/<hash>.<ext>`, keeping one copy.
+///
+/// References in the other parts' `.tex` files are rewritten in place. The
+/// returned map (`part/name` to `shared/hash`, both without extension) is for
+/// rewriting the references of `part_name` itself with `rewrite_media_paths`.
+pub fn pool_duplicate_images<P: AsRef<Path>>(
+    project_dir: P,
+    part_name: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let project_dir = project_dir.as_ref();
+    let media_dir = project_dir.join("media");
+    let shared_dir = media_dir.join(SHARED_DIR);
+
+    // Images of the other parts first, so they are found as the earlier copy
+    let mut images = Vec::new();
+    let mut own = Vec::new();
+    for entry in read_dir(&media_dir)? {
+        let dir = entry?.path();
+        let Some(name) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !dir.is_dir() || name == SHARED_DIR {
+            continue;
+        }
+        for file in read_dir(&dir)? {
+            let file = file?.path();
+            // Charts are generated per part, and not images
+            if file.is_file() && file.extension().is_some_and(|ext| ext != "tex") {
+                if name == part_name {
+                    own.push(file);
+                } else {
+                    images.push(file);
+                }
+            }
+        }
+    }
+    images.sort();
+    own.sort();
+
+    let mut by_hash: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for path in &images {
+        by_hash
+            .entry(content_hash(&read(path)?))
+            .or_default()
+            .push(path.clone());
+    }
+
+    let mut pooled = HashMap::new();
+    let mut rewrites: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for path in own {
+        let contents = read(&path)?;
+        let hash = content_hash(&contents);
+        let ext = path.extension().unwrap().to_string_lossy().to_string();
+        let shared_name = format!("{SHARED_DIR}/{hash:016x}");
+        let shared_path = shared_dir.join(format!("{hash:016x}.{ext}"));
+
+        if !shared_path.exists() {
+            // Hash collisions are told apart by comparing the contents
+            let mut earlier = None;
+            for candidate in by_hash.get(&hash).into_iter().flatten() {
+                if candidate.extension() == path.extension() && read(candidate)? == contents {
+                    earlier = Some(candidate.clone());
+                    break;
+                }
+            }
+            match earlier {
+                Some(earlier) => {
+                    create_dir_all(&shared_dir)?;
+                    rename(&earlier, &shared_path)?;
+                    let (part, name) = media_name(&media_dir, &earlier);
+                    rewrites
+                        .entry(part.clone())
+                        .or_default()
+                        .insert(format!("{part}/{name}"), shared_name.clone());
+                    if part == part_name {
+                        pooled.insert(format!("{part}/{name}"), shared_name.clone());
+                    }
+                }
+                None => {
+                    by_hash.entry(hash).or_default().push(path.clone());
+                    continue;
+                }
+            }
+        } else if read(&shared_path)? != contents {
+            continue;
+        }
+
+        remove_file(&path)?;
+        let (_, name) = media_name(&media_dir, &path);
+        pooled.insert(format!("{part_name}/{name}"), shared_name);
+    }
+
+    // Other parts whose copy moved to the pool
+    for (part, paths) in rewrites.iter().filter(|(part, _)| *part != part_name) {
+        let mut files = Vec::new();
+        collect_files(&project_dir.join("src").join(part), &mut files)?;
+        for file in files
+            .iter()
+            .filter(|f| f.extension().is_some_and(|ext| ext == "tex"))
+        {
+            let contents = read_to_string(file)?;
+            let rewritten = rewrite_media_paths(&contents, paths);
+            write_if_changed(file, rewritten)?;
+        }
+    }
+
+    if !pooled.is_empty() {
+        println!(
+            "Moved {} duplicate images to media/{}",
+            pooled.len(),
+            SHARED_DIR
+        );
+    }
+    Ok(pooled)
+}
+
+/// Replaces `{part/name}` image paths, as used by `\fig` and `\subfig`,
+/// by their entries in `paths`
+pub fn rewrite_media_paths(text: &str, paths: &HashMap<String, String>) -> String {
+    if paths.is_empty() {
+        return text.to_string();
+    }
+    let re = Regex::new(r"\{([^{}/\s]+/[^{}/\s]+)\}").unwrap();
+    re.replace_all(text, |caps: &regex::Captures| match paths.get(&caps[1]) {
+        Some(path) => format!("{{{path}}}"),
+        None => caps[0].to_string(),
+    })
+    .into()
+}
+
+/// Part directory and file name without extension of a file in `media/`
+fn media_name(media_dir: &Path, path: &Path) -> (String, String) {
+    let part = path
+        .parent()
+        .and_then(|dir| dir.strip_prefix(media_dir).ok())
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = path.file_stem().unwrap().to_string_lossy().to_string();
+    (part, name)
+}
+
+/// Part of `line` before an unescaped `%`
+fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '%' if !escaped => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+/// The file an image path refers to, trying the graphicx extensions for a
+/// path without one
+fn resolve(project_dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = project_dir.join(path);
+    if path.extension().is_some() && path.is_file() {
+        return Some(path);
+    }
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|ext| {
+            let mut name = path.clone().into_os_string();
+            name.push(".");
+            name.push(ext);
+            PathBuf::from(name)
+        })
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::io::Write;
+
+    /// A DOCX whose only drawing is `image1.png`, the fallback of `image2.svg`
+    fn write_docx(path: &Path) {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("word/document.xml", options).unwrap();
+        zip.write_all(
+            br#"<w:drawing><wp:extent cx="100"/><a:blip r:embed="rId1"><asvg:svgBlip r:embed="rId2"/></a:blip></w:drawing>"#,
+        )
+        .unwrap();
+        zip.start_file("word/_rels/document.xml.rels", options)
+            .unwrap();
+        zip.write_all(
+            br#"<Relationship Id="rId1" Target="media/image1.png"/><Relationship Id="rId2" Target="media/image2.svg"/>"#,
+        )
+        .unwrap();
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn fallbacks_and_template_images_are_not_orphans() {
+        let project = std::env::temp_dir().join(format!("media-audit-{}", std::process::id()));
+        let media = project.join("media");
+        create_dir_all(media.join("p1")).unwrap();
+        create_dir_all(project.join("src/p1")).unwrap();
+        write_docx(&project.join("src/p1/p1.docx"));
+        write(
+            project.join("main.tex"),
+            "\\fig{p1/image2}\n\\fig{p1/gone}\n",
+        )
+        .unwrap();
+        for name in [
+            "envelope.png",
+            "p1/image1.png",
+            "p1/image2.svg",
+            "p1/image2.pdf",
+            "p1/stray.png",
+        ] {
+            write(media.join(name), "").unwrap();
+        }
+
+        let audit = audit_media(&project).unwrap();
+        std::fs::remove_dir_all(&project).unwrap();
+        assert_eq!(audit.orphans, [media.join("p1/stray.png")]);
+        assert_eq!(audit.dangling.len(), 1);
+        assert_eq!(audit.dangling[0].path, "media/p1/gone");
+    }
+
+    #[test]
+    fn duplicate_images_are_pooled_once() {
+        let project = std::env::temp_dir().join(format!("media-pool-{}", std::process::id()));
+        let media = project.join("media");
+        let shared = |ext: &str| format!("{SHARED_DIR}/{:016x}.{ext}", content_hash(b"logo"));
+        let files = |part: &str| {
+            let mut files = Vec::new();
+            collect_files(&media.join(part), &mut files).unwrap();
+            files
+        };
+        for dir in ["media/p1", "media/p2", "media/p3", "src/p1"] {
+            create_dir_all(project.join(dir)).unwrap();
+        }
+        write(media.join("p1/image1.png"), "logo").unwrap();
+        write(project.join("src/p1/001.tex"), "\\fig{p1/image1}{Logo}\n").unwrap();
+
+        // The copy of another part moves to the pool and its reference follows
+        write(media.join("p2/image5.png"), "logo").unwrap();
+        let pooled = pool_duplicate_images(&project, "p2").unwrap();
+        let hash = format!("{:016x}", content_hash(b"logo"));
+        assert_eq!(pooled["p2/image5"], format!("{SHARED_DIR}/{hash}"));
+        assert!(media.join(shared("png")).is_file());
+        assert!(files("p1").is_empty() && files("p2").is_empty());
+        assert_eq!(
+            read_to_string(project.join("src/p1/001.tex")).unwrap(),
+            format!("\\fig{{{SHARED_DIR}/{hash}}}{{Logo}}\n")
+        );
+
+        // Re-importing a pooled part reuses the shared file
+        write(media.join("p2/image5.png"), "logo").unwrap();
+        let pooled = pool_duplicate_images(&project, "p2").unwrap();
+        assert_eq!(pooled["p2/image5"], format!("{SHARED_DIR}/{hash}"));
+        assert!(files("p2").is_empty());
+
+        // Twice in one part, and the same bytes as another extension
+        write(media.join("p3/image1.png"), "photo").unwrap();
+        write(media.join("p3/image2.png"), "photo").unwrap();
+        write(media.join("p3/image3.jpg"), "logo").unwrap();
+        let pooled = pool_duplicate_images(&project, "p3").unwrap();
+        let photo = format!("{SHARED_DIR}/{:016x}", content_hash(b"photo"));
+        assert_eq!(pooled.len(), 2);
+        assert_eq!(
+            (&pooled["p3/image1"], &pooled["p3/image2"]),
+            (&photo, &photo)
+        );
+        assert_eq!(files("p3"), [media.join("p3/image3.jpg")]);
+        assert!(!media.join(shared("jpg")).exists());
+
+        std::fs::remove_dir_all(&project).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use pandoc_types::definition::{Block, Caption, Format, Inline, Pandoc, Row, Table, Target};
+
+/// Runs the journal passes on a pandoc JSON document and returns it as JSON.
+/// A document with constructors or an API version `pandoc_types` does not
+/// know is an error, so the caller can fall back to a direct conversion.
+pub fn apply_passes(document: &str) -> Result<String, serde_json::Error> {
+    let mut document: Pandoc = serde_json::from_str(document)?;
+    document.blocks = blocks(std::mem::take(&mut document.blocks));
+    serde_json::to_string(&document)
+}
+
+fn blocks(blocks: Vec<Block>) -> Vec<Block> {
+    let mut result = Vec::new();
+    for block in blocks {
+        match block {
+            Block::BulletList(items) => {
+                for item in items {
+                    result.extend(list_item(item, "-".to_string()));
+                }
+            }
+            Block::OrderedList(attributes, items) => {
+                let start = attributes.start_number;
+                for (n, item) in items.into_iter().enumerate() {
+                    result.extend(list_item(item, format!("{}.", start + n as i32)));
+                }
+            }
+            Block::Para(content) => result.push(Block::Para(inlines(content))),
+            Block::Plain(content) => result.push(Block::Plain(inlines(content))),
+            Block::LineBlock(lines) => {
+                result.push(Block::LineBlock(lines.into_iter().map(inlines).collect()))
+            }
+            Block::Header(level, attributes, content) => {
+                result.push(Block::Header(level, attributes, inlines(content)))
+            }
+            Block::BlockQuote(content) => result.push(Block::BlockQuote(self::blocks(content))),
+            Block::DefinitionList(items) => result.push(Block::DefinitionList(
+                items
+                    .into_iter()
+                    .map(|(term, definitions)| {
+                        (
+                            inlines(term),
+                            definitions.into_iter().map(self::blocks).collect(),
+                        )
+                    })
+                    .collect(),
+            )),
+            Block::Table(table) => result.push(Block::Table(self::table(table))),
+            Block::Figure(attributes, caption, content) => result.push(Block::Figure(
+                attributes,
+                self::caption(caption),
+                self::blocks(content),
+            )),
+            Block::Div(attributes, content) => {
+                result.push(Block::Div(attributes, self::blocks(content)))
+            }
+            Block::Null => {}
+            other @ (Block::CodeBlock(..) | Block::RawBlock(..) | Block::HorizontalRule) => {
+                result.push(other)
+            }
+        }
+    }
+    result
+}
+
+/// Lists are typeset as numbered or dashed paragraphs, as the journal does
+/// not use list environments. Nested lists are flattened.
+fn list_item(item: Vec<Block>, marker: String) -> Vec<Block> {
+    let mut blocks = blocks(item);
+    match blocks.first_mut() {
+        Some(Block::Plain(content) | Block::Para(content)) => {
+            let mut text = vec![Inline::Str(marker), Inline::Space];
+            text.append(content);
+            blocks[0] = Block::Para(text);
+        }
+        _ => blocks.insert(0, Block::Para(vec![Inline::Str(marker)])),
+    }
+    blocks
+}
+
+fn table(mut table: Table) -> Table {
+    let rows = |rows: Vec<Row>| -> Vec<Row> {
+        rows.into_iter()
+            .map(|mut row| {
+                for cell in &mut row.cells {
+                    cell.content = blocks(std::mem::take(&mut cell.content));
+                }
+                row
+            })
+            .collect()
+    };
+    table.caption = caption(table.caption);
+    table.head.rows = rows(table.head.rows);
+    for body in &mut table.bodies {
+        body.head = rows(std::mem::take(&mut body.head));
+        body.body = rows(std::mem::take(&mut body.body));
+    }
+    table.foot.rows = rows(table.foot.rows);
+    table
+}
+
+fn caption(caption: Caption) -> Caption {
+    Caption {
+        short: caption.short.map(inlines),
+        long: blocks(caption.long),
+    }
+}
+
+fn inlines(inlines: Vec<Inline>) -> Vec<Inline> {
+    let mut result = Vec::new();
+    for inline in inlines {
+        match inline {
+            // Bold single punctuation marks and letters are Word leftovers
+            Inline::Strong(content) => match content.as_slice() {
+                [Inline::Str(s)] if is_short_symbol(s) => result.push(Inline::Str(s.clone())),
+                _ => result.push(Inline::Strong(self::inlines(content))),
+            },
+            Inline::Superscript(content) => {
+                result.push(raw_latex("\\tsp{"));
+                result.extend(self::inlines(content));
+                result.push(raw_latex("}"));
+            }
+            Inline::Subscript(content) => {
+                result.push(raw_latex("\\tsb{"));
+                result.extend(self::inlines(content));
+                result.push(raw_latex("}"));
+            }
+            // E-mail addresses are printed as plain text
+            Inline::Link(_, _, Target { url, .. }) if url.starts_with("mailto:") => {
+                result.push(Inline::Str(url["mailto:".len()..].to_string()))
+            }
+            Inline::Link(attributes, content, target) => {
+                result.push(Inline::Link(attributes, self::inlines(content), target))
+            }
+            Inline::Image(attributes, content, target) => {
+                result.push(Inline::Image(attributes, self::inlines(content), target))
+            }
+            Inline::Emph(content) => result.push(Inline::Emph(self::inlines(content))),
+            Inline::Underline(content) => result.push(Inline::Underline(self::inlines(content))),
+            Inline::Strikeout(content) => result.push(Inline::Strikeout(self::inlines(content))),
+            Inline::SmallCaps(content) => result.push(Inline::SmallCaps(self::inlines(content))),
+            Inline::Quoted(kind, content) => {
+                result.push(Inline::Quoted(kind, self::inlines(content)))
+            }
+            Inline::Cite(citations, content) => {
+                result.push(Inline::Cite(citations, self::inlines(content)))
+            }
+            Inline::Span(attributes, content) => {
+                result.push(Inline::Span(attributes, self::inlines(content)))
+            }
+            Inline::Note(content) => result.push(Inline::Note(blocks(content))),
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+fn is_short_symbol(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!((chars.next(), chars.next()), (Some(c), None) if !c.is_whitespace() && !c.is_ascii_digit())
+}
+
+fn raw_latex(text: &str) -> Inline {
+    Inline::RawInline(Format("latex".to_string()), text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the passes on `blocks` and renders each resulting block as text,
+    /// with raw LaTeX kept as is
+    fn render(blocks: &str) -> Vec<String> {
+        let document = format!(
+            r#"{{"pandoc-api-version":[1,23,1],"meta":{{}},"blocks":{}}}"#,
+            blocks
+        );
+        let output: Pandoc = serde_json::from_str(&apply_passes(&document).unwrap()).unwrap();
+        output
+            .blocks
+            .iter()
+            .map(|block| match block {
+                Block::Para(content) | Block::Plain(content) => text(content),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    fn text(inlines: &[Inline]) -> String {
+        inlines
+            .iter()
+            .map(|inline| match inline {
+                Inline::Str(s) => s.clone(),
+                Inline::Space | Inline::SoftBreak => " ".to_string(),
+                Inline::RawInline(Format(format), s) if format == "latex" => s.clone(),
+                Inline::Strong(content) => format!("\\textbf{{{}}}", text(content)),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn superscripts_and_subscripts_become_tsp_and_tsb() {
+        let blocks = r#"[{"t":"Para","c":[
+            {"t":"Str","c":"m"},{"t":"Superscript","c":[{"t":"Str","c":"2"}]},
+            {"t":"Space"},{"t":"Str","c":"H"},{"t":"Subscript","c":[{"t":"Str","c":"2"}]},
+            {"t":"Str","c":"O"}]}]"#;
+        assert_eq!(render(blocks), ["m\\tsp{2} H\\tsb{2}O"]);
+    }
+
+    #[test]
+    fn lists_become_numbered_paragraphs() {
+        let blocks = r#"[{"t":"OrderedList","c":[[3,{"t":"Decimal"},{"t":"Period"}],[
+            [{"t":"Plain","c":[{"t":"Str","c":"one"}]},
+             {"t":"BulletList","c":[[{"t":"Plain","c":[{"t":"Str","c":"nested"}]}]]}],
+            [{"t":"Plain","c":[{"t":"Str","c":"two"}]}]]]}]"#;
+        assert_eq!(render(blocks), ["3. one", "- nested", "4. two"]);
+    }
+
+    #[test]
+    fn single_bold_symbols_and_mail_links_are_plain_text() {
+        let blocks = r#"[{"t":"Para","c":[
+            {"t":"Strong","c":[{"t":"Str","c":":"}]},{"t":"Space"},
+            {"t":"Strong","c":[{"t":"Str","c":"Bold"}]},{"t":"Space"},
+            {"t":"Link","c":[["",[],[]],[{"t":"Str","c":"a@x.kz"}],["mailto:a@x.kz",""]]}]}]"#;
+        assert_eq!(render(blocks), [": \\textbf{Bold} a@x.kz"]);
+    }
+
+    #[test]
+    fn passes_reach_table_cells() {
+        let cell = r#"[["",[],[]],{"t":"AlignDefault"},1,1,
+            [{"t":"Plain","c":[{"t":"Str","c":"x"},{"t":"Superscript","c":[{"t":"Str","c":"2"}]}]}]]"#;
+        let blocks = format!(
+            r#"[{{"t":"Table","c":[["",[],[]],[null,[]],[[{{"t":"AlignDefault"}},{{"t":"ColWidthDefault"}}]],
+            [["",[],[]],[]],[[["",[],[]],0,[],[[["",[],[]],[{}]]]]],[["",[],[]],[]]]}}]"#,
+            cell
+        );
+        let rendered = render(&blocks);
+        assert!(rendered[0].contains(r#"RawInline(Format("latex"), "\\tsp{")"#));
+    }
+
+    #[test]
+    fn unknown_constructors_and_api_versions_are_errors() {
+        let unknown = r#"{"pandoc-api-version":[1,23,1],"meta":{},
+            "blocks":[{"t":"Para","c":[]},{"t":"Marquee","c":[]}]}"#;
+        assert!(apply_passes(unknown).is_err());
+
+        let old = r#"{"pandoc-api-version":[1,22,2],"meta":{},"blocks":[]}"#;
+        assert!(apply_passes(old).is_err());
+    }
+
+    #[test]
+    fn superscript_envelopes_reach_latex_as_envelope() {
+        use crate::latex_ext::LatexStringExt;
+
+        let blocks = r#"[{"t":"Para","c":[{"t":"Str","c":"Ivanov"},
+            {"t":"Superscript","c":[{"t":"Str","c":"🖂"}]}]}]"#;
+        let mut latex = render(blocks).concat();
+        latex.replace_envelopes();
+        assert_eq!(latex, r"Ivanov\envelope ");
+    }
+}
@@ -1,31 +1,455 @@
-use std::path::Path;
-use std::process::Command;
+use colored::*;
+use regex::Regex;
 
-pub fn run_pandoc<P: AsRef<Path>>(input_path: P) -> String {
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::string::FromUtf8Error;
+use std::thread;
+
+use crate::pandoc_ast::apply_passes;
+use crate::util::content_hash;
+
+/// Oldest pandoc whose LaTeX output the cleanup passes can handle
+const MIN_SUPPORTED: [u32; 2] = [2, 11];
+/// Range of pandoc versions the cleanup passes were checked against
+const TESTED_FROM: [u32; 2] = [3, 1];
+const TESTED_UNTIL: [u32; 2] = [3, 7];
+
+/// A pandoc version such as 3.1.11.1, compared component by component
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PandocVersion(pub Vec<u32>);
+
+impl PandocVersion {
+    /// Reads the version from the first line of `pandoc --version`
+    fn parse(output: &str) -> Option<Self> {
+        let re = Regex::new(r"^pandoc(?:\.exe)?\s+(\d+(?:\.\d+)*)").unwrap();
+        let caps = re.captures(output.lines().next()?)?;
+        let parts: Option<Vec<u32>> = caps[1].split('.').map(|n| n.parse().ok()).collect();
+        Some(PandocVersion(parts?))
+    }
+
+    /// Whether this version is `version` or newer, ignoring later components
+    fn at_least(&self, version: &[u32]) -> bool {
+        self.0.as_slice() >= version
+    }
+
+    /// Whether this version is `version`.x or older
+    fn at_most(&self, version: &[u32]) -> bool {
+        self.0
+            .iter()
+            .take(version.len())
+            .copied()
+            .collect::<Vec<_>>()
+            .as_slice()
+            <= version
+    }
+}
+
+impl fmt::Display for PandocVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|n| n.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// Directory in a project holding cached pandoc outputs
+pub const CACHE_DIR: &str = ".cache/pandoc";
+
+/// pandoc outputs stored in the project, keyed by a hash of the input, the
+/// pandoc version and the arguments. Deleting the directory is always safe.
+///
+/// Entries are named `<source>.<args>.<hash>`, so the entry of a DOCX that
+/// has changed since is found and removed when the new one is written.
+#[derive(Debug, Clone)]
+pub struct PandocCache {
+    dir: PathBuf,
+}
+
+impl PandocCache {
+    pub fn new<P: AsRef<Path>>(project_dir: P) -> Self {
+        PandocCache {
+            dir: project_dir.as_ref().join(CACHE_DIR),
+        }
+    }
+
+    /// Returns the cached output for this input, or runs pandoc and caches it.
+    /// `source` names the document, usually the DOCX file stem. `args` must
+    /// not contain file paths, so a moved DOCX still hits.
+    fn get_or_run(
+        &self,
+        source: &str,
+        version: &PandocVersion,
+        args: &[&str],
+        input: &[u8],
+        run: impl FnOnce() -> Result<String, PandocError>,
+    ) -> Result<String, PandocError> {
+        let mut key = format!("{}\0{}\0", version, args.join(" ")).into_bytes();
+        key.extend_from_slice(input);
+        let slug: Vec<&str> = args.iter().map(|a| a.trim_start_matches('-')).collect();
+        let prefix = format!("{}.{}.", source, slug.join("-"));
+        let path = self
+            .dir
+            .join(format!("{}{:016x}", prefix, content_hash(&key)));
+
+        let output = match fs::read_to_string(&path) {
+            Ok(output) => output,
+            Err(_) => {
+                let output = run()?;
+                // A failed write only costs the next run its cache hit
+                if let Err(e) = self.write(&path, &output) {
+                    eprintln!(
+                        "[PANDOC]: Could not cache output in {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+                output
+            }
+        };
+        self.prune(&prefix, &path);
+        Ok(output)
+    }
+
+    /// Writes through a temporary file, so an interrupted run never leaves a
+    /// truncated entry behind
+    fn write(&self, path: &Path, output: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, output)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Removes the other entries of the same source and arguments, which
+    /// belong to earlier inputs, and temporary files left by interrupted runs
+    fn prune(&self, prefix: &str, current: &Path) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let stale = path != current
+                && path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(prefix));
+            if stale {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+/// A DOCX read by pandoc into its JSON AST
+#[derive(Debug)]
+pub struct PandocDocument {
+    input: PathBuf,
+    /// Name of the cache entries, the DOCX file stem
+    source: String,
+    json: String,
+    cache: PandocCache,
+    pub version: PandocVersion,
+}
+
+impl PandocDocument {
+    /// Runs the journal passes on the AST and renders it as LaTeX. If the AST
+    /// cannot be processed, the DOCX is converted to LaTeX directly and left to
+    /// the string passes.
+    pub fn to_latex(&self) -> Result<String, PandocError> {
+        match apply_passes(&self.json) {
+            Ok(json) => {
+                let args = ["-f", "json", "-t", "latex"];
+                self.cache
+                    .get_or_run(&self.source, &self.version, &args, json.as_bytes(), || {
+                        pandoc(&args, Some(&json))
+                    })
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "[PANDOC]: Could not process the AST, converting directly: {}",
+                        e
+                    )
+                    .yellow()
+                );
+                let input = self.input.to_str().unwrap();
+                let docx = fs::read(&self.input)
+                    .map_err(|e| PandocError::ReadInput(self.input.clone(), e))?;
+                let args = ["-f", "docx", "-t", "latex"];
+                self.cache
+                    .get_or_run(&self.source, &self.version, &args, &docx, || {
+                        pandoc(&[&[input], &args[..]].concat(), None)
+                    })
+            }
+        }
+    }
+}
+
+/// Why converting a DOCX with pandoc failed
+#[derive(Debug)]
+pub enum PandocError {
+    /// The input file does not exist
+    InputMissing(PathBuf),
+    /// The input path cannot be passed to pandoc
+    InvalidPath(PathBuf),
+    /// pandoc is not on the PATH
+    NotInstalled,
+    /// The input file could not be read
+    ReadInput(PathBuf, io::Error),
+    /// pandoc could not be started for another reason
+    Spawn(io::Error),
+    /// `pandoc --version` printed something unexpected
+    UnknownVersion(String),
+    /// pandoc is older than the cleanup passes support
+    Unsupported(PandocVersion),
+    /// pandoc ran but reported an error
+    Failed { status: ExitStatus, stderr: String },
+    /// pandoc produced output that is not UTF-8
+    InvalidOutput(FromUtf8Error),
+    /// The thread feeding pandoc its input panicked
+    WriterPanicked,
+}
+
+impl fmt::Display for PandocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PandocError::InputMissing(path) => {
+                write!(f, "[PANDOC]: Input file '{}' does not exist", path.display())
+            }
+            PandocError::InvalidPath(path) => {
+                write!(f, "[PANDOC]: Input path '{}' is not valid UTF-8", path.display())
+            }
+            PandocError::NotInstalled => write!(
+                f,
+                "[PANDOC]: pandoc is not installed or not on the PATH, see https://pandoc.org/installing.html"
+            ),
+            PandocError::ReadInput(path, e) => {
+                write!(f, "[PANDOC]: Could not read '{}': {}", path.display(), e)
+            }
+            PandocError::Spawn(e) => write!(f, "[PANDOC]: Failed to execute pandoc: {}", e),
+            PandocError::UnknownVersion(output) => write!(
+                f,
+                "[PANDOC]: Could not read the version from 'pandoc --version': {}",
+                output.lines().next().unwrap_or("")
+            ),
+            PandocError::Unsupported(version) => write!(
+                f,
+                "[PANDOC]: pandoc {} is not supported, {}.{} or newer is required",
+                version, MIN_SUPPORTED[0], MIN_SUPPORTED[1]
+            ),
+            PandocError::Failed { status, stderr } => {
+                write!(f, "[PANDOC]: pandoc failed ({}):\n{}", status, stderr.trim_end())
+            }
+            PandocError::InvalidOutput(e) => {
+                write!(f, "[PANDOC]: Output is not valid UTF-8: {}", e)
+            }
+            PandocError::WriterPanicked => {
+                write!(f, "[PANDOC]: Writing the input to pandoc failed unexpectedly")
+            }
+        }
+    }
+}
+
+impl Error for PandocError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PandocError::ReadInput(_, e) | PandocError::Spawn(e) => Some(e),
+            PandocError::InvalidOutput(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn spawn_error(e: io::Error) -> PandocError {
+    match e.kind() {
+        io::ErrorKind::NotFound => PandocError::NotInstalled,
+        _ => PandocError::Spawn(e),
+    }
+}
+
+/// Runs `pandoc --version` and checks the version against the supported
+/// range, warning about versions the cleanup passes were not tested with
+pub fn pandoc_version() -> Result<PandocVersion, PandocError> {
+    let output = Command::new("pandoc")
+        .arg("--version")
+        .output()
+        .map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(PandocError::Failed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        });
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = PandocVersion::parse(&stdout)
+        .ok_or_else(|| PandocError::UnknownVersion(stdout.to_string()))?;
+
+    if !version.at_least(&MIN_SUPPORTED) {
+        return Err(PandocError::Unsupported(version));
+    }
+    if !version.at_least(&TESTED_FROM) || !version.at_most(&TESTED_UNTIL) {
+        eprintln!(
+            "{}",
+            format!(
+                "[PANDOC]: pandoc {} is untested, output was checked with {}.{} to {}.{}. Review the converted articles.",
+                version, TESTED_FROM[0], TESTED_FROM[1], TESTED_UNTIL[0], TESTED_UNTIL[1]
+            )
+            .yellow()
+        );
+    }
+    Ok(version)
+}
+
+/// Reads `input_path` with pandoc into its JSON AST, after checking the
+/// pandoc version. An unchanged DOCX is read from `cache`.
+pub fn read_docx<P: AsRef<Path>>(
+    input_path: P,
+    cache: &PandocCache,
+) -> Result<PandocDocument, PandocError> {
     let path = input_path.as_ref();
 
     // Ensure the input file exists
     if !path.exists() {
-        panic!("[PANDOC]: Input file does not exist");
+        return Err(PandocError::InputMissing(path.to_path_buf()));
     }
 
     let input_str = path
         .to_str()
-        .expect("[PANDOC]: Input path is not valid UTF-8");
+        .ok_or_else(|| PandocError::InvalidPath(path.to_path_buf()))?;
 
-    let output = Command::new("pandoc")
-        .arg(input_str)
-        .arg("-f")
-        .arg("docx")
-        .arg("-t")
-        .arg("latex")
-        .output()
-        .expect("[PANDOC]: Failed to execute pandoc command");
+    let version = pandoc_version()?;
+    let docx = fs::read(path).map_err(|e| PandocError::ReadInput(path.to_path_buf(), e))?;
+    let args = ["-f", "docx", "-t", "json"];
+    let source = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("document")
+        .to_string();
+    let json = cache.get_or_run(&source, &version, &args, &docx, || {
+        pandoc(&[&[input_str], &args[..]].concat(), None)
+    })?;
+
+    Ok(PandocDocument {
+        input: path.to_path_buf(),
+        source,
+        json,
+        cache: cache.clone(),
+        version,
+    })
+}
+
+/// Runs pandoc with `args`, feeding it `stdin` if given, and returns its output
+fn pandoc(args: &[&str], stdin: Option<&str>) -> Result<String, PandocError> {
+    let mut child = Command::new("pandoc")
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+
+    // Written from another thread, so a full stdout pipe cannot block pandoc
+    let writer = match (stdin, child.stdin.take()) {
+        (Some(input), Some(mut pipe)) => {
+            let input = input.to_string();
+            Some(thread::spawn(move || pipe.write_all(input.as_bytes())))
+        }
+        _ => None,
+    };
+
+    let output = child.wait_with_output().map_err(PandocError::Spawn)?;
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .map_err(|_| PandocError::WriterPanicked)?
+            .map_err(PandocError::Spawn)?;
+    }
 
     if output.status.success() {
-        String::from_utf8(output.stdout).expect("[PANDOC]: Output is not valid UTF-8")
+        String::from_utf8(output.stdout).map_err(PandocError::InvalidOutput)
     } else {
-        let err_msg = String::from_utf8_lossy(&output.stderr);
-        panic!("[PANDOC]: Pandoc error:\n{}", err_msg);
+        Err(PandocError::Failed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_version_output() {
+        let version = PandocVersion::parse("pandoc 3.1.11.1\nFeatures: +server +lua\n");
+        assert_eq!(version, Some(PandocVersion(vec![3, 1, 11, 1])));
+        let version = PandocVersion::parse("pandoc.exe 2.19.2\r\n");
+        assert_eq!(version, Some(PandocVersion(vec![2, 19, 2])));
+        assert_eq!(PandocVersion::parse("pandoc-citeproc 0.17"), None);
+        assert_eq!(PandocVersion::parse(""), None);
+    }
+
+    #[test]
+    fn compares_versions() {
+        let version = PandocVersion(vec![3, 1, 11]);
+        assert!(version.at_least(&[3, 1]));
+        assert!(version.at_least(&[3, 1, 11]));
+        assert!(version.at_least(&[2, 11]));
+        assert!(!version.at_least(&[3, 2]));
+        assert!(!version.at_least(&[3, 1, 12]));
+        assert!(version.at_most(&[3, 1]));
+        assert!(version.at_most(&[3, 7]));
+        assert!(!version.at_most(&[3, 0]));
+        assert!(!PandocVersion(vec![3]).at_least(&[3, 1]));
+    }
+
+    #[test]
+    fn cache_keeps_one_entry_per_source() {
+        let project = std::env::temp_dir().join(format!("pandoc-cache-{}", std::process::id()));
+        let cache = PandocCache::new(&project);
+        let version = PandocVersion(vec![3, 6]);
+        let args = ["-f", "docx", "-t", "json"];
+        let entries = || {
+            let mut names: Vec<String> = fs::read_dir(project.join(CACHE_DIR))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let first = cache.get_or_run("p1", &version, &args, b"v1", || Ok("one".to_string()));
+        assert_eq!(first.unwrap(), "one");
+        let hit = cache.get_or_run("p1", &version, &args, b"v1", || panic!("not cached"));
+        assert_eq!(hit.unwrap(), "one");
+        cache
+            .get_or_run("p2", &version, &args, b"v1", || Ok("other".to_string()))
+            .unwrap();
+
+        // An interrupted write, then a changed DOCX
+        let stale = entries()
+            .into_iter()
+            .find(|n| n.starts_with("p1."))
+            .unwrap();
+        fs::write(project.join(CACHE_DIR).join(stale + ".tmp"), "trunc").unwrap();
+        let second = cache.get_or_run("p1", &version, &args, b"v2", || Ok("two".to_string()));
+        assert_eq!(second.unwrap(), "two");
+
+        let names = entries();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].starts_with("p1.f-docx-t-json.") && names[1].starts_with("p2."));
+        assert_eq!(
+            fs::read_to_string(project.join(CACHE_DIR).join(&names[0])).unwrap(),
+            "two"
+        );
+        fs::remove_dir_all(&project).unwrap();
     }
 }
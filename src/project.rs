@@ -1,12 +1,20 @@
-use crate::images::extract_images_from_docx;
+use crate::article::{markup_title_block, Article};
+use crate::charts::extract_charts_from_docx;
+use crate::glyphs::{find_missing_glyphs, format_missing_glyphs};
+use crate::images::{extract_images_from_docx, format_image_report, FigureSizing};
 use crate::latex_ext::LatexStringExt;
-use crate::pandoc_ext::run_pandoc;
-use crate::util::copy_recursively;
+use crate::manifest::{render_templates, Build, Driver, Engine, Manifest};
+use crate::media::{pool_duplicate_images, rewrite_media_paths};
+use crate::pandoc_ext::{read_docx, PandocCache};
+use crate::tex_log::{format_summary, TexLog};
+use crate::util::{collect_files, content_hash, copy_recursively, write_if_changed};
 use colored::*;
+use include_dir::{include_dir, Dir};
 
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::fs::{copy, create_dir_all, read_to_string, write};
+use std::fs::{copy, create_dir_all, read_dir, read_to_string, remove_file, write};
 use std::io;
 use std::path::{Path, PathBuf};
 
@@ -14,24 +22,42 @@ use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::sync::mpsc::channel;
 use std::time::{Duration, Instant};
 
-pub fn create_project<P: AsRef<Path>>(project_dir: P) -> Result<(), Box<dyn Error>> {
-    let project_dir = project_dir.as_ref();
+/// Default template, compiled into the binary
+pub static TEMPLATE: Dir = include_dir!("$CARGO_MANIFEST_DIR/template");
+
+/// Environment variable overriding the embedded template
+pub const TEMPLATE_ENV: &str = "MAKE_VESTNIK_TEMPLATE";
 
-    // Get the absolute path of the current executable
-    let exe_path = env::current_exe()?;
-    let exe_dir = exe_path.parent().unwrap();
+/// Creates a project from `template`, falling back to `$MAKE_VESTNIK_TEMPLATE`
+/// and then to the template embedded in the binary.
+pub fn create_project<P: AsRef<Path>>(
+    project_dir: P,
+    template: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let project_dir = project_dir.as_ref();
 
-    // Go up to the project root (two levels up from target/debug)
-    let project_root = exe_dir
-        .parent()
-        .and_then(|p| p.parent())
-        .ok_or_else(|| "Could not find project root")?;
+    let template_path = template
+        .map(Path::to_path_buf)
+        .or_else(|| env::var_os(TEMPLATE_ENV).map(PathBuf::from));
 
-    // Construct the template path relative to the project root
-    let template_path = project_root.join("template");
+    match template_path {
+        Some(template_path) => {
+            if !template_path.is_dir() {
+                return Err(
+                    format!("Template directory '{}' not found", template_path.display()).into(),
+                );
+            }
+            println!("Using template from '{}'", template_path.display());
+            copy_recursively(&template_path, project_dir)?;
+        }
+        None => {
+            create_dir_all(project_dir)?;
+            TEMPLATE.extract(project_dir)?;
+        }
+    }
 
-    // Copy template recursively
-    copy_recursively(&template_path, project_dir)?;
+    // Render issue metadata from the manifest shipped with the template
+    render_templates(project_dir)?;
 
     Ok(())
 }
@@ -49,10 +75,22 @@ pub fn update_project<P: AsRef<Path>, Q: AsRef<Path>>(
         .and_then(|s| s.to_str())
         .ok_or("Failed to extract part name from input path")?;
 
+    // Convert first, so a pandoc failure leaves the project untouched
+    let document = read_docx(input_path, &PandocCache::new(project_dir))?;
+    println!("Read with pandoc {}", document.version);
+    // Typed passes on the pandoc AST here. Images stay string passes below:
+    // which file a figure points at (converted SVG or its raster fallback)
+    // is only known after extraction, and the docx fallback has no AST.
+    let mut text = document.to_latex()?;
+
     // Create directory for article .tex files
     let part_dir = Path::new(project_dir).join("src").join(part_name);
     create_dir_all(&part_dir)?;
-    copy(input_path, part_dir.join(input_path.file_name().unwrap()))?;
+    // Keep a copy for 'rebuild', which imports from that copy itself
+    let docx_copy = part_dir.join(input_path.file_name().unwrap());
+    if !docx_copy.exists() || !same_file(input_path, &docx_copy)? {
+        copy(input_path, &docx_copy)?;
+    }
 
     // Create media directory
     let media_dir = Path::new(project_dir).join("media").join(part_name);
@@ -60,57 +98,247 @@ pub fn update_project<P: AsRef<Path>, Q: AsRef<Path>>(
 
     let main_path = Path::new(project_dir).join("main.tex");
 
-    // Run Pandoc and process text
-    let mut text = run_pandoc(input_path);
+    // Figure widths come from the image sizes in the DOCX
+    let (figures, images) = match Manifest::load(project_dir)? {
+        Some(manifest) => (manifest.figures, manifest.images),
+        None => Default::default(),
+    };
+    let mut sizing = FigureSizing::from_docx(input_path, figures.min_width, figures.max_width)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Could not read image sizes from DOCX, using default widths: {}",
+                e
+            );
+            FigureSizing::default()
+        });
+
+    // Charts are not images in the DOCX, they are redrawn with pgfplots
+    let charts = extract_charts_from_docx(input_path, &media_dir).unwrap_or_else(|e| {
+        eprintln!("Could not read charts from DOCX: {}", e);
+        Vec::new()
+    });
+
+    // Extract images, with a print-quality report next to the articles
+    let (reports, converted) = extract_images_from_docx(
+        input_path.to_str().unwrap(),
+        media_dir.to_str().unwrap(),
+        &images,
+        &sizing,
+    )?;
+    for report in reports.iter().filter(|r| r.is_low_resolution(&images)) {
+        eprintln!(
+            "{}",
+            format!(
+                "Low resolution image {}: {:.0} dpi at its printed size",
+                report.name,
+                report.dpi.unwrap_or_default()
+            )
+            .yellow()
+        );
+    }
+    write_if_changed(
+        part_dir.join("images.txt"),
+        format_image_report(part_name, &reports),
+    )?;
+
+    // Figures use an SVG instead of its PNG fallback once it is converted
+    sizing.drop_unconverted_svgs(&converted);
+
+    // Images already in another part (or twice in this one) are kept once
+    let pooled = pool_duplicate_images(project_dir, part_name)?;
+
     text.replace_textbf();
     text.remove_short_bfseries()?;
+    text.remove_labelenumi();
     text.fix_lists();
     text.fix_number_spacing()?;
-    text.remove_tag("ul");
-    text.remove_tag("hl");
-    text.remove_tag("pandocbounded");
-    text.comment_out_tables();
+    let underlined = text.unwrap_tag("ul");
+    let highlighted = text.unwrap_tag("hl");
+    let bounded = text.unwrap_tag("pandocbounded");
+    text.group_images(part_name, &sizing);
+    text.convert_tables();
     text.change_latex_quotes();
     text.replace_envelopes();
     text.remove_tightlists();
     text.unindent();
     text.replace_bullets();
-    text.fix_images(part_name);
+    text.fix_images(part_name, &sizing);
+    let charted = text.insert_charts(part_name, &charts, &sizing);
+    text.attach_figure_captions();
     text.replace_super_sub_scripts();
     text.fix_email_links();
 	text.remove_zero_hspace();
 	text.replace_textless();
+    text = rewrite_media_paths(&text, &pooled);
+
+    println!(
+        "Unwrapped {} \\ul, {} \\hl and {} \\pandocbounded commands, contents kept",
+        underlined, highlighted, bounded
+    );
+    if charted < charts.len() {
+        eprintln!(
+            "{}",
+            format!(
+                "Inserted {} of {} charts, the rest are left as [CHART]",
+                charted,
+                charts.len()
+            )
+            .yellow()
+        );
+    }
 
     // Split into individual articles
     let articles: Vec<String> = text.split_articles();
 
-    // Write each article into its own numbered .tex file
+    // Write each article into its own numbered .tex file, with its metadata
+    // in a .toml file of the same name
     for (i, article) in articles.iter().enumerate() {
         let file_path = part_dir.join(format!("{:03}.tex", i + 1));
-        write(file_path, article)?;
+        write_if_changed(&file_path, markup_title_block(article))?;
+
+        let metadata = toml::to_string_pretty(&Article::parse(article))?;
+        write_if_changed(file_path.with_extension("toml"), metadata)?;
     }
 
-    // Extract images
-    extract_images_from_docx(input_path.to_str().unwrap(), media_dir.to_str().unwrap())?;
+    // Remove articles left over from a previous import with more articles
+    remove_stale_articles(&part_dir, articles.len())?;
 
-    let articles_main: String = (0..articles.len())
-        .map(|i| format!("\n\\input{{src/{part_name}/{:03}.tex}}", i + 1))
-        .collect();
+    // Replace (or add) this part's managed block in main.tex
     let contents = read_to_string(&main_path)?;
+    let new_content = upsert_part_block(&contents, part_name, articles.len())?;
+    if new_content != contents {
+        write(&main_path, new_content)?;
+    }
+
+    Ok(())
+}
+
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(a.canonicalize()? == b.canonicalize()?)
+}
+
+/// Imports every part again from the DOCX copy in `src/<part>/`, in the
+/// order of main.tex. pandoc output is taken from the cache where possible.
+pub fn rebuild_project<P: AsRef<Path>>(project_dir: P) -> Result<usize, Box<dyn Error>> {
+    let project_dir = project_dir.as_ref();
+    let contents = read_to_string(project_dir.join("main.tex"))?;
+    let parts: Vec<&str> = contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("% BEGIN PART: "))
+        .collect();
+
+    for part in &parts {
+        let docx = project_dir
+            .join("src")
+            .join(part)
+            .join(format!("{part}.docx"));
+        if !docx.exists() {
+            return Err(format!("Part '{}' has no DOCX at {}", part, docx.display()).into());
+        }
+        println!("{}", format!("Rebuilding part {}", part).bold());
+        update_project(&docx, project_dir)?;
+    }
+    Ok(parts.len())
+}
+
+fn part_block_markers(part_name: &str) -> (String, String) {
+    (
+        format!("% BEGIN PART: {part_name}"),
+        format!("% END PART: {part_name}"),
+    )
+}
 
-    let mut new_content = String::new();
-    let mut inserted = false;
+/// Replaces the `% BEGIN PART` / `% END PART` block of `part_name` in main.tex.
+/// A part seen for the first time is added after the last managed block, or
+/// after `% Main content` if there is none yet.
+fn upsert_part_block(
+    contents: &str,
+    part_name: &str,
+    article_count: usize,
+) -> Result<String, Box<dyn Error>> {
+    let (begin, end) = part_block_markers(part_name);
+    let legacy_prefix = format!("\\input{{src/{part_name}/");
+
+    let mut block = vec![begin.clone()];
+    block.extend((1..=article_count).map(|i| format!("\\input{{src/{part_name}/{i:03}.tex}}")));
+    block.push(end.clone());
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut replaced = false;
+    let mut inside = false;
 
     for line in contents.lines() {
-        new_content.push_str(line);
-        new_content.push('\n');
-        if !inserted && line.trim() == "% Main content" {
-            new_content.push_str(&articles_main);
-            inserted = true;
+        let trimmed = line.trim();
+        if inside {
+            if trimmed == end {
+                inside = false;
+            }
+            continue;
+        }
+        if trimmed == begin {
+            if replaced {
+                return Err(format!("Duplicate block for part '{part_name}' in main.tex").into());
+            }
+            lines.extend(block.iter().cloned());
+            replaced = true;
+            inside = true;
+            continue;
         }
+        // Drop \input lines of this part written before blocks were introduced
+        if trimmed.starts_with(&legacy_prefix) {
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+
+    if inside {
+        return Err(format!("Missing '{end}' in main.tex").into());
+    }
+
+    if !replaced {
+        let anchor = lines
+            .iter()
+            .rposition(|l| l.trim().starts_with("% END PART: "))
+            .or_else(|| lines.iter().position(|l| l.trim() == "% Main content"))
+            .ok_or("Could not find '% Main content' in main.tex")?;
+        let mut insertion = vec![String::new()];
+        insertion.extend(block);
+        lines.splice(anchor + 1..anchor + 1, insertion);
     }
 
-    write(&main_path, new_content)?;
+    let mut new_content = lines.join("\n");
+    if contents.ends_with('\n') {
+        new_content.push('\n');
+    }
+    Ok(new_content)
+}
+
+/// Deletes `NNN.tex` and `NNN.toml` files in `part_dir` numbered above `article_count`.
+fn remove_stale_articles(part_dir: &Path, article_count: usize) -> io::Result<()> {
+    // An import that found nothing is more likely broken than an empty part
+    if article_count == 0 {
+        return Err(io::Error::other(format!(
+            "No articles found, keeping the existing articles in {}",
+            part_dir.display()
+        )));
+    }
+    for entry in read_dir(part_dir)? {
+        let path = entry?.path();
+        let is_stale = path
+            .extension()
+            .is_some_and(|ext| ext == "tex" || ext == "toml")
+            && path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .filter(|s| s.len() == 3)
+                .and_then(|s| s.parse::<usize>().ok())
+                .is_some_and(|n| n > article_count);
+
+        if is_stale {
+            println!("Removing stale article {}", path.display());
+            remove_file(&path)?;
+        }
+    }
     Ok(())
 }
 
@@ -124,12 +352,11 @@ pub fn watch_and_compile_project(project_dir: Option<PathBuf>) -> io::Result<()>
     compile_project(&project_dir, &build_dir);
 
     let (tx, rx) = channel();
-    let mut watcher = RecommendedWatcher::new(tx, Config::default())
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut watcher = RecommendedWatcher::new(tx, Config::default()).map_err(io::Error::other)?;
 
     watcher
         .watch(&project_dir, RecursiveMode::Recursive)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        .map_err(io::Error::other)?;
 
     let mut last_event = Instant::now();
     let debounce_duration = Duration::from_millis(500);
@@ -173,28 +400,123 @@ pub fn watch_and_compile_project(project_dir: Option<PathBuf>) -> io::Result<()>
     }
 }
 
-fn compile_project(project_dir: &PathBuf, build_dir: &PathBuf) {
+/// Compiles the project once into `project_dir/build`, without watching.
+/// Returns whether the PDF was produced.
+pub fn build_project<P: AsRef<Path>>(project_dir: P) -> io::Result<bool> {
+    let project_dir = project_dir.as_ref();
+    let build_dir = project_dir.join("build");
+    create_dir_all(&build_dir)?;
+    Ok(compile_project(project_dir, &build_dir))
+}
+
+/// Runs xelatex until the auxiliary files stop changing and the log has no
+/// rerun hints, at most `MAX_PASSES` times. Progress goes to stderr, so a
+/// one-shot build keeps stdout free.
+fn compile_project(project_dir: &Path, build_dir: &Path) -> bool {
+    const MAX_PASSES: usize = 5;
+
     let start_time = Instant::now();
 
-    let status = std::process::Command::new("xelatex")
-        .args(&[
-            "-interaction=nonstopmode",
-            "-halt-on-error",
-            "-output-directory",
-            &build_dir.display().to_string(),
-            "main.tex",
-        ])
-        .current_dir(project_dir)
-        .status();
+    // Regenerate first pages etc. from vestnik.toml before every run
+    if let Err(e) = render_templates(project_dir) {
+        eprintln!("{}", format!("Failed to render templates: {}", e).red());
+        return false;
+    }
+
+    let settings = match Manifest::load(project_dir) {
+        Ok(manifest) => manifest.map(|m| m.build).unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", format!("Failed to read build settings: {}", e).red());
+            return false;
+        }
+    };
+    let output_dir = build_dir.strip_prefix(project_dir).unwrap_or(build_dir);
+    let (program, args, reruns_itself) = match build_command(&settings, output_dir) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e.red());
+            return false;
+        }
+    };
+
+    let mut pass = 1;
+    let mut state = auxiliary_state(build_dir);
+    let status = loop {
+        let pass_start = Instant::now();
+        let status = std::process::Command::new(program)
+            .args(&args)
+            .current_dir(project_dir)
+            .stdout(io::stderr())
+            .status();
+
+        let previous = std::mem::replace(&mut state, auxiliary_state(build_dir));
+        let reason = match &status {
+            Ok(status) if status.success() && !reruns_itself => {
+                rerun_reason(build_dir, &previous, &state)
+            }
+            _ => None,
+        };
+        eprintln!(
+            "{}",
+            format!(
+                "Pass {} finished in {:.2}s{}",
+                pass,
+                pass_start.elapsed().as_secs_f64(),
+                reason
+                    .as_ref()
+                    .map(|r| format!(" ({})", r))
+                    .unwrap_or_default()
+            )
+            .cyan()
+        );
+
+        match reason {
+            Some(_) if pass < MAX_PASSES => pass += 1,
+            Some(_) => {
+                eprintln!(
+                    "{}",
+                    format!("Output still changing after {} passes", MAX_PASSES).yellow()
+                );
+                break status;
+            }
+            None => break status,
+        }
+    };
 
     let duration = start_time.elapsed();
 
+    let log = TexLog::read(build_dir.join("main.log"), settings.engine).unwrap_or_default();
+    if !matches!(&status, Ok(status) if status.success()) {
+        for error in &log.errors {
+            eprintln!(
+                "{}",
+                format!("{}: {}", error.location.display(), error.message).red()
+            );
+            if let Some(excerpt) = error.location.excerpt(project_dir) {
+                eprintln!("{}", excerpt);
+            }
+        }
+    }
+    if log.has_warnings() {
+        eprintln!("{}", format_summary(&log).yellow());
+    }
+    let glyphs = find_missing_glyphs(project_dir, &log);
+    if !glyphs.is_empty() {
+        eprintln!("{}", format_missing_glyphs(&glyphs).yellow());
+    }
+
     match status {
         Ok(status) if status.success() => {
-            println!(
+            eprintln!(
                 "{}",
-                format!("Compilation succeeded! ({:.2}s)", duration.as_secs_f64()).green()
-            )
+                format!(
+                    "Compilation succeeded! ({} passes, {:.2}s)",
+                    pass,
+                    duration.as_secs_f64()
+                )
+                .green()
+            );
+            true
         }
         Ok(status) => {
             eprintln!(
@@ -205,20 +527,114 @@ fn compile_project(project_dir: &PathBuf, build_dir: &PathBuf) {
                     duration.as_secs_f64()
                 )
                 .red()
-            )
+            );
+            false
         }
         Err(e) => {
             eprintln!(
                 "{}",
                 format!(
-                    "Failed to run xelatex: {} ({:.2}s)",
+                    "Failed to run {}: {} ({:.2}s)",
+                    program,
                     e,
                     duration.as_secs_f64()
                 )
                 .red()
+            );
+            false
+        }
+    }
+}
+
+/// Program and arguments of one compilation run, and whether the program
+/// reruns TeX by itself until the output settles
+fn build_command(
+    settings: &Build,
+    output_dir: &Path,
+) -> Result<(&'static str, Vec<String>, bool), String> {
+    let output_dir = output_dir.display().to_string();
+    let (program, mut args, reruns_itself) = match (settings.driver, settings.engine) {
+        (Driver::Direct, Engine::Tectonic) => (
+            "tectonic",
+            vec![
+                "--keep-logs".to_string(),
+                "--keep-intermediates".to_string(),
+                "--outdir".to_string(),
+                output_dir,
+            ],
+            true,
+        ),
+        (Driver::Direct, engine) => (
+            engine.program(),
+            vec![
+                "-interaction=nonstopmode".to_string(),
+                "-halt-on-error".to_string(),
+                "-output-directory".to_string(),
+                output_dir,
+            ],
+            false,
+        ),
+        (Driver::Latexmk, Engine::Tectonic) => {
+            return Err(
+                "latexmk cannot drive tectonic; set driver = \"direct\" in vestnik.toml"
+                    .to_string(),
             )
         }
+        (Driver::Latexmk, engine) => (
+            "latexmk",
+            vec![
+                format!("-{}", engine.program()),
+                "-interaction=nonstopmode".to_string(),
+                "-halt-on-error".to_string(),
+                format!("-outdir={}", output_dir),
+            ],
+            true,
+        ),
+    };
+    args.extend(settings.args.iter().cloned());
+    args.push("main.tex".to_string());
+    Ok((program, args, reruns_itself))
+}
+
+/// Hashes of the files TeX reads back on the next run (.aux, .toc, ...)
+fn auxiliary_state(build_dir: &Path) -> HashMap<PathBuf, u64> {
+    let mut files = Vec::new();
+    let _ = collect_files(build_dir, &mut files);
+    files
+        .into_iter()
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| matches!(ext, "aux" | "toc" | "lof" | "lot" | "out"))
+        })
+        .filter_map(|path| {
+            let hash = content_hash(&std::fs::read(&path).ok()?);
+            Some((path, hash))
+        })
+        .collect()
+}
+
+/// Why another pass is needed after a successful one, if it is
+fn rerun_reason(
+    build_dir: &Path,
+    previous: &HashMap<PathBuf, u64>,
+    current: &HashMap<PathBuf, u64>,
+) -> Option<String> {
+    let log = read_to_string(build_dir.join("main.log")).unwrap_or_default();
+    if log.contains("Rerun to get") || log.contains("Label(s) may have changed") {
+        return Some("log asks for a rerun".to_string());
     }
+    let mut changed: Vec<_> = current
+        .iter()
+        .filter(|(path, hash)| previous.get(*path) != Some(hash))
+        .filter_map(|(path, _)| path.file_name())
+        .map(|name| name.to_string_lossy())
+        .collect();
+    if changed.is_empty() {
+        return None;
+    }
+    changed.sort();
+    Some(format!("{} changed", changed.join(", ")))
 }
 
 fn should_ignore(path: &Path) -> bool {
@@ -234,6 +650,140 @@ fn should_ignore(path: &Path) -> bool {
         return true;
     }
 
-    // Ignore files in 'build' directory
-    path.components().any(|c| c.as_os_str() == "build")
+    // Ignore files in 'build' and the pandoc cache
+    path.components()
+        .any(|c| c.as_os_str() == "build" || c.as_os_str() == ".cache")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(
+        engine: Engine,
+        driver: Driver,
+    ) -> Result<(&'static str, Vec<String>, bool), String> {
+        let settings = Build {
+            engine,
+            driver,
+            args: vec!["-shell-escape".to_string()],
+        };
+        build_command(&settings, Path::new("build"))
+    }
+
+    #[test]
+    fn build_command_covers_every_engine_and_driver() {
+        let (program, args, reruns) = command(Engine::Xelatex, Driver::Direct).unwrap();
+        assert_eq!((program, reruns), ("xelatex", false));
+        assert!(args.contains(&"-output-directory".to_string()));
+
+        let (program, _, reruns) = command(Engine::Lualatex, Driver::Direct).unwrap();
+        assert_eq!((program, reruns), ("lualatex", false));
+
+        let (program, args, reruns) = command(Engine::Tectonic, Driver::Direct).unwrap();
+        assert_eq!((program, reruns), ("tectonic", true));
+        assert!(args.starts_with(&["--keep-logs".to_string()]));
+
+        let (program, args, reruns) = command(Engine::Xelatex, Driver::Latexmk).unwrap();
+        assert_eq!((program, reruns), ("latexmk", true));
+        assert_eq!(args[0], "-xelatex");
+        assert!(args.contains(&"-outdir=build".to_string()));
+
+        let (_, args, _) = command(Engine::Lualatex, Driver::Latexmk).unwrap();
+        assert_eq!(args[0], "-lualatex");
+
+        assert!(command(Engine::Tectonic, Driver::Latexmk).is_err());
+    }
+
+    #[test]
+    fn extra_args_come_just_before_main_tex() {
+        for driver in [Driver::Direct, Driver::Latexmk] {
+            for engine in [Engine::Xelatex, Engine::Lualatex, Engine::Tectonic] {
+                let Ok((_, args, _)) = command(engine, driver) else {
+                    continue;
+                };
+                assert_eq!(args[args.len() - 2..], ["-shell-escape", "main.tex"]);
+            }
+        }
+    }
+
+    const MAIN: &str = "\\begin{document}\n% Main content\n\n\\end{document}\n";
+
+    #[test]
+    fn part_blocks_are_inserted_in_order() {
+        let first = upsert_part_block(MAIN, "p1", 2).unwrap();
+        assert_eq!(
+            first,
+            "\\begin{document}\n% Main content\n\n% BEGIN PART: p1\n\\input{src/p1/001.tex}\n\\input{src/p1/002.tex}\n% END PART: p1\n\n\\end{document}\n"
+        );
+
+        let second = upsert_part_block(&first, "p2", 1).unwrap();
+        assert!(second.contains(
+            "% END PART: p1\n\n% BEGIN PART: p2\n\\input{src/p2/001.tex}\n% END PART: p2\n"
+        ));
+    }
+
+    #[test]
+    fn part_blocks_are_replaced_in_place() {
+        let both = upsert_part_block(&upsert_part_block(MAIN, "p1", 2).unwrap(), "p2", 1).unwrap();
+        let replaced = upsert_part_block(&both, "p1", 1).unwrap();
+        assert_eq!(replaced, both.replace("\\input{src/p1/002.tex}\n", ""));
+
+        // Nothing changed, nothing written
+        assert_eq!(upsert_part_block(&replaced, "p1", 1).unwrap(), replaced);
+    }
+
+    #[test]
+    fn legacy_inputs_are_replaced_by_the_block() {
+        let legacy = MAIN.replace(
+            "% Main content\n",
+            "% Main content\n\\input{src/p1/001.tex}\n\\input{src/p1/002.tex}\n\\input{src/p2/001.tex}\n",
+        );
+        let updated = upsert_part_block(&legacy, "p1", 1).unwrap();
+        assert_eq!(updated.matches("\\input{src/p1/").count(), 1);
+        assert!(updated.contains("\\input{src/p2/001.tex}\n"));
+    }
+
+    #[test]
+    fn broken_part_blocks_are_errors() {
+        let unterminated = MAIN.replace("% Main content\n", "% Main content\n% BEGIN PART: p1\n");
+        assert!(upsert_part_block(&unterminated, "p1", 1).is_err());
+
+        let block = "% BEGIN PART: p1\n% END PART: p1\n";
+        let duplicated = MAIN.replace(
+            "% Main content\n",
+            &format!("% Main content\n{block}{block}"),
+        );
+        assert!(upsert_part_block(&duplicated, "p1", 1).is_err());
+
+        assert!(upsert_part_block("\\begin{document}\n", "p1", 1).is_err());
+    }
+
+    #[test]
+    fn stale_articles_above_the_count_are_removed() {
+        let part_dir = std::env::temp_dir().join(format!("stale-articles-{}", std::process::id()));
+        create_dir_all(&part_dir).unwrap();
+        let names = [
+            "001.tex",
+            "001.toml",
+            "002.tex",
+            "002.toml",
+            "003.tex",
+            "p1.docx",
+            "images.txt",
+        ];
+        for name in names {
+            write(part_dir.join(name), "").unwrap();
+        }
+
+        assert!(remove_stale_articles(&part_dir, 0).is_err());
+        remove_stale_articles(&part_dir, 1).unwrap();
+        let mut left: Vec<String> = read_dir(&part_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&part_dir).unwrap();
+        assert_eq!(left, ["001.tex", "001.toml", "images.txt", "p1.docx"]);
+    }
 }
@@ -0,0 +1,414 @@
+use regex::Regex;
+
+use std::fs::{read, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::manifest::Engine;
+
+/// TeX wraps log lines at this width (`max_print_line`), counted in
+/// characters by XeTeX and in bytes by LuaTeX
+const LOG_LINE_WIDTH: usize = 79;
+
+/// Lines after a blank one in which an error's `l.NNN` line may still come,
+/// past the help text LaTeX prints for its own errors
+const HELP_TEXT_LINES: usize = 5;
+
+/// Locations listed per kind of warning in the summary
+const SUMMARY_LOCATIONS: usize = 5;
+
+/// A line of a file read by TeX, as far as the log tells
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+}
+
+/// A `!` error of the log
+#[derive(Debug)]
+pub struct LogError {
+    pub message: String,
+    pub location: Location,
+}
+
+/// A "Missing character" warning: a glyph the font does not have
+#[derive(Debug)]
+pub struct MissingCharacter {
+    pub character: String,
+    pub font: String,
+    pub location: Location,
+}
+
+/// What `parse_log` found in a TeX log
+#[derive(Debug, Default)]
+pub struct TexLog {
+    pub errors: Vec<LogError>,
+    pub overfull: Vec<Location>,
+    pub underfull: Vec<Location>,
+    pub missing_characters: Vec<MissingCharacter>,
+    /// Undefined references and citations, by label
+    pub undefined_references: Vec<(String, Location)>,
+}
+
+impl TexLog {
+    pub fn read<P: AsRef<Path>>(path: P, engine: Engine) -> Option<Self> {
+        read(path).ok().map(|log| parse_log(&log, engine))
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        !self.overfull.is_empty()
+            || !self.underfull.is_empty()
+            || !self.undefined_references.is_empty()
+    }
+}
+
+/// Parses the log of a TeX run, following the `(file` ... `)` nesting to
+/// tell which file each error and warning comes from
+pub fn parse_log(log: &[u8], engine: Engine) -> TexLog {
+    let re_line = Regex::new(r"^l\.(\d+) ?(.*)$").unwrap();
+    let re_box = Regex::new(r"^(Over|Under)full \\[hv]box (?:.*at lines? (\d+))?").unwrap();
+    let re_missing =
+        Regex::new(r"^Missing character: There is no (\S+)(?: \(U\+[0-9A-F]+\))? in font ([^!]*)!")
+            .unwrap();
+    let re_reference = Regex::new(
+        r"(?:Reference|Citation) `([^']*)' on page \S+ undefined(?: on input line (\d+))?",
+    )
+    .unwrap();
+
+    let lines = unwrap_lines(log, engine);
+    let mut log_result = TexLog::default();
+    let mut stack: Vec<Option<PathBuf>> = Vec::new();
+    // Index of the error still waiting for its `l.NNN` line
+    let mut open_error: Option<usize> = None;
+    // Inside the box contents TeX prints after an over/underfull warning
+    let mut in_box = false;
+
+    let current_file = |stack: &[Option<PathBuf>]| stack.iter().rev().find_map(|file| file.clone());
+
+    for (n, line) in lines.iter().enumerate() {
+        if in_box && !line.starts_with("! ") {
+            in_box = !line.trim().is_empty();
+            continue;
+        }
+        in_box = false;
+
+        if let Some(message) = line.strip_prefix("! ") {
+            // Follow-ups of the first error that say nothing new
+            if (message.starts_with("==> Fatal error") || message == "Emergency stop.")
+                && !log_result.errors.is_empty()
+            {
+                continue;
+            }
+            log_result.errors.push(LogError {
+                message: message.trim().to_string(),
+                location: Location {
+                    file: current_file(&stack),
+                    line: None,
+                },
+            });
+            open_error = Some(log_result.errors.len() - 1);
+            continue;
+        }
+
+        if let Some(i) = open_error {
+            // An error without a line number ends at a blank line, unless
+            // its `l.NNN` line follows LaTeX's help text
+            if line.trim().is_empty() {
+                let line_follows = lines[n + 1..]
+                    .iter()
+                    .take(HELP_TEXT_LINES)
+                    .take_while(|next| !next.starts_with("! "))
+                    .any(|next| re_line.is_match(next));
+                if !line_follows {
+                    open_error = None;
+                }
+                continue;
+            }
+            if let Some(caps) = re_line.captures(line) {
+                let error = &mut log_result.errors[i];
+                error.location.line = caps[1].parse().ok();
+                // The token TeX did not know is the last one read
+                if error.message == "Undefined control sequence." {
+                    if let Some(token) = caps[2].split_whitespace().last() {
+                        error.message = format!("Undefined control sequence {}", token);
+                    }
+                }
+                open_error = None;
+            }
+            // Help text and context lines are not file names
+            continue;
+        }
+
+        if let Some(caps) = re_box.captures(line) {
+            let location = Location {
+                file: current_file(&stack),
+                line: caps.get(2).and_then(|m| m.as_str().parse().ok()),
+            };
+            if &caps[1] == "Over" {
+                log_result.overfull.push(location);
+            } else {
+                log_result.underfull.push(location);
+            }
+            in_box = true;
+            continue;
+        }
+
+        if let Some(caps) = re_missing.captures(line) {
+            log_result.missing_characters.push(MissingCharacter {
+                character: caps[1].to_string(),
+                // "[Times New Roman]:mapping=tex-text;" is Times New Roman
+                font: caps[2]
+                    .split(':')
+                    .next()
+                    .unwrap_or_default()
+                    .trim_matches(['[', ']', '"', ' '])
+                    .to_string(),
+                location: Location {
+                    file: current_file(&stack),
+                    line: None,
+                },
+            });
+            continue;
+        }
+
+        if let Some(caps) = re_reference.captures(line) {
+            log_result.undefined_references.push((
+                caps[1].to_string(),
+                Location {
+                    file: current_file(&stack),
+                    line: caps.get(2).and_then(|m| m.as_str().parse().ok()),
+                },
+            ));
+            continue;
+        }
+
+        track_files(line, &mut stack);
+    }
+
+    log_result
+}
+
+/// Joins the lines `engine` broke at `LOG_LINE_WIDTH`. LuaTeX may break
+/// inside a character, so lines are only decoded once joined.
+fn unwrap_lines(log: &[u8], engine: Engine) -> Vec<String> {
+    let width = |line: &[u8]| match engine {
+        Engine::Lualatex => line.len(),
+        Engine::Xelatex | Engine::Tectonic => String::from_utf8_lossy(line).chars().count(),
+    };
+
+    let mut lines = Vec::new();
+    let mut current = Vec::new();
+    let log = log.strip_suffix(b"\n").unwrap_or(log);
+    for line in log.split(|&byte| byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        current.extend_from_slice(line);
+        if width(line) != LOG_LINE_WIDTH {
+            lines.push(String::from_utf8_lossy(&current).into_owned());
+            current.clear();
+        }
+    }
+    if !current.is_empty() {
+        lines.push(String::from_utf8_lossy(&current).into_owned());
+    }
+    lines
+}
+
+/// Updates the stack of open files from the parentheses of a log line.
+/// A `(` followed by something that is not a file name is pushed as `None`
+/// so that its `)` does not close a file.
+fn track_files(line: &str, stack: &mut Vec<Option<PathBuf>>) {
+    let mut rest = line;
+    while let Some(i) = rest.find(['(', ')']) {
+        if rest[i..].starts_with(')') {
+            stack.pop();
+            rest = &rest[i + 1..];
+            continue;
+        }
+        let after = &rest[i + 1..];
+        let end = after
+            .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .unwrap_or(after.len());
+        let name = &after[..end];
+        let is_file = (name.starts_with('.') || name.starts_with('/'))
+            || name.rsplit_once('.').is_some_and(|(stem, ext)| {
+                !stem.is_empty()
+                    && !ext.is_empty()
+                    && ext.chars().all(|c| c.is_ascii_alphanumeric())
+            });
+        stack.push(is_file.then(|| PathBuf::from(name)));
+        rest = &after[end..];
+    }
+}
+
+impl Location {
+    /// `src/<part>/NNN.tex:line`, relative to the project
+    pub fn display(&self) -> String {
+        let file = match &self.file {
+            Some(file) => file.strip_prefix(".").unwrap_or(file).display().to_string(),
+            None => "main.tex".to_string(),
+        };
+        match self.line {
+            Some(line) => format!("{}:{}", file, line),
+            None => file,
+        }
+    }
+
+    /// The source line and its neighbours, numbered
+    pub fn excerpt(&self, project_dir: &Path) -> Option<String> {
+        let file = project_dir.join(self.file.as_ref()?);
+        let line = self.line?;
+        let contents = read_to_string(file).ok()?;
+        let lines: Vec<&str> = contents.lines().collect();
+        let first = line.saturating_sub(2).max(1);
+        let last = (line + 1).min(lines.len());
+        let excerpt = (first..=last)
+            .map(|n| {
+                let marker = if n == line { '>' } else { ' ' };
+                format!("  {} {:>4} | {}", marker, n, lines[n - 1])
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        (!excerpt.is_empty()).then_some(excerpt)
+    }
+}
+
+/// One line per kind of warning, with the first few locations. Missing
+/// glyphs have their own report, `format_missing_glyphs`.
+pub fn format_summary(log: &TexLog) -> String {
+    let mut summary = Vec::new();
+    let mut add = |count: usize, what: &str, locations: Vec<String>| {
+        if count == 0 {
+            return;
+        }
+        let mut shown: Vec<String> = Vec::new();
+        for location in locations {
+            if !shown.contains(&location) {
+                shown.push(location);
+            }
+        }
+        let more = shown.len().saturating_sub(SUMMARY_LOCATIONS);
+        shown.truncate(SUMMARY_LOCATIONS);
+        if more > 0 {
+            shown.push(format!("{} more", more));
+        }
+        summary.push(format!("{} {}: {}", count, what, shown.join(", ")));
+    };
+
+    add(
+        log.overfull.len(),
+        "overfull boxes",
+        log.overfull.iter().map(Location::display).collect(),
+    );
+    add(
+        log.underfull.len(),
+        "underfull boxes",
+        log.underfull.iter().map(Location::display).collect(),
+    );
+    add(
+        log.undefined_references.len(),
+        "undefined references",
+        log.undefined_references
+            .iter()
+            .map(|(label, location)| format!("{} ({})", label, location.display()))
+            .collect(),
+    );
+    summary.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(log: &str) -> TexLog {
+        parse_log(log.as_bytes(), Engine::Xelatex)
+    }
+
+    #[test]
+    fn unwrap_lines_counts_characters_or_bytes_by_engine() {
+        // 79 characters, but 80 bytes
+        let wrapped = format!("{}ә\nrest\nnext", "a".repeat(78));
+        assert_eq!(
+            unwrap_lines(wrapped.as_bytes(), Engine::Xelatex),
+            [format!("{}әrest", "a".repeat(78)), "next".to_string()]
+        );
+        assert_eq!(unwrap_lines(wrapped.as_bytes(), Engine::Lualatex).len(), 3);
+
+        // LuaTeX breaks after 79 bytes, in the middle of "ә"
+        let mut broken = "b".repeat(78).into_bytes();
+        broken.extend_from_slice(&"ә".as_bytes()[..1]);
+        broken.push(b'\n');
+        broken.extend_from_slice(&"ә".as_bytes()[1..]);
+        broken.extend_from_slice(b"\r\n");
+        assert_eq!(
+            unwrap_lines(&broken, Engine::Lualatex),
+            [format!("{}ә", "b".repeat(78))]
+        );
+    }
+
+    #[test]
+    fn track_files_ignores_parentheses_that_are_not_files() {
+        let mut stack = Vec::new();
+        track_files("(./main.tex (/usr/share/texmf/article.cls", &mut stack);
+        track_files("Document Class: article (LaTeX)", &mut stack);
+        track_files(") (./src/p1/001.tex", &mut stack);
+        assert_eq!(
+            stack,
+            [
+                Some(PathBuf::from("./main.tex")),
+                Some(PathBuf::from("./src/p1/001.tex"))
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_and_warnings_are_located() {
+        let log = parse(
+            r"(./main.tex (./src/p1/001.tex
+! Undefined control sequence.
+l.12 Text \foo
+               
+Overfull \hbox (3.0pt too wide) in paragraph at lines 20--21
+[]\TU/TimesNewRoman(0)/m/n/12 word
+ 
+Missing character: There is no ә (U+04D9) in font [Times New Roman]:mapping=tex-text;!
+LaTeX Warning: Reference `fig:1' on page 2 undefined on input line 30.
+)
+Underfull \vbox (badness 10000) has occurred while \output is active
+! Emergency stop.
+<*> main.tex
+)",
+        );
+        assert_eq!(log.errors.len(), 1);
+        assert_eq!(log.errors[0].message, r"Undefined control sequence \foo");
+        assert_eq!(log.errors[0].location.display(), "src/p1/001.tex:12");
+        assert_eq!(log.overfull[0].display(), "src/p1/001.tex:20");
+        assert_eq!(log.underfull[0].display(), "main.tex");
+        assert_eq!(log.missing_characters[0].character, "ә");
+        assert_eq!(log.missing_characters[0].font, "Times New Roman");
+        assert_eq!(log.undefined_references[0].0, "fig:1");
+        assert_eq!(log.undefined_references[0].1.display(), "src/p1/001.tex:30");
+    }
+
+    #[test]
+    fn errors_without_a_line_end_at_a_blank_line() {
+        let log = parse(
+            r"(./main.tex
+! LaTeX Error: Environment foo undefined.
+
+See the LaTeX manual or LaTeX Companion for explanation.
+Type  H <return>  for immediate help.
+ ...
+                                                  
+l.5 \begin{foo}
+
+! I can't write on file `main.pdf'.
+(Press Enter to retry, or Control-D to exit; default file extension is `.pdf')
+
+(./src/p1/001.tex
+Overfull \hbox (1.0pt too wide) in paragraph at lines 7--7
+)",
+        );
+        assert_eq!(log.errors[0].location.line, Some(5));
+        assert_eq!(log.errors[1].location.line, None);
+        assert_eq!(log.overfull[0].display(), "src/p1/001.tex:7");
+    }
+}